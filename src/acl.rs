@@ -0,0 +1,41 @@
+use std::error::Error;
+
+use crate::node::{Node, verify_signature};
+use crate::transaction::Acl;
+
+impl Acl {
+    fn signing_bytes(&self) -> Vec<u8> {
+        serde_json::to_vec(&(&self.request_id, &self.owner, &self.readers)).unwrap_or_default()
+    }
+
+    pub fn new(
+        node: &Node,
+        request_id: &str,
+        readers: Vec<String>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let mut acl = Acl {
+            request_id: request_id.to_string(),
+            owner: node.id.clone(),
+            readers,
+            signature: vec![],
+            public_key: node.public_key.clone(),
+        };
+        acl.signature = node.private_key.sign(&acl.signing_bytes())?;
+
+        Ok(acl)
+    }
+
+    pub fn verify(&self) -> Result<bool, Box<dyn Error>> {
+        verify_signature(
+            &self.owner,
+            &self.public_key,
+            &self.signing_bytes(),
+            &self.signature,
+        )
+    }
+
+    /// The owner may always read its own file
+    pub fn allows(&self, peer_id: &str) -> bool {
+        self.owner == peer_id || self.readers.iter().any(|reader| reader == peer_id)
+    }
+}
@@ -0,0 +1,245 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::config::Config;
+
+/// Storage for the content-addressed chunks a node holds, keyed by their hash
+pub trait BlobStore {
+    /// Stores the blob unless it is already present, failing when it does not fit the capacity
+    fn put(&mut self, key: &str, blob: &[u8]) -> Result<(), Box<dyn Error>>;
+    fn get(&self, key: &str) -> Result<Vec<u8>, Box<dyn Error>>;
+    fn contains(&self, key: &str) -> bool;
+    /// Returns whether there was a blob to remove
+    fn remove(&mut self, key: &str) -> bool;
+    /// Bytes that can still be stored before the capacity limit is reached
+    fn available(&self) -> u64;
+}
+
+/// Opens the backend chosen in the config
+pub fn open(config: &Config) -> Result<Box<dyn BlobStore>, Box<dyn Error>> {
+    if config.in_memory {
+        return Ok(Box::new(MemoryBlobStore::new(config.capacity)));
+    }
+    Ok(Box::new(FsBlobStore::open(
+        config.blob_dir(),
+        config.capacity,
+    )?))
+}
+
+/// Keys come from the chain and from peers, so only accept what a chunk hash looks like
+fn check_key(key: &str) -> Result<(), Box<dyn Error>> {
+    if key.len() != 64 || !key.chars().all(|c| matches!(c, '0'..='9' | 'a'..='f')) {
+        return Err(format!("invalid blob key {key}").into());
+    }
+    Ok(())
+}
+
+fn check_capacity(used: u64, capacity: u64, size: usize) -> Result<(), Box<dyn Error>> {
+    if used + size as u64 > capacity {
+        return Err("blob store is full".into());
+    }
+    Ok(())
+}
+
+fn dir_size(dir: &Path) -> u64 {
+    fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| match entry.file_type() {
+            Ok(kind) if kind.is_dir() => dir_size(&entry.path()),
+            _ => entry.metadata().map_or(0, |meta| meta.len()),
+        })
+        .sum()
+}
+
+/// Keeps every blob in its own file under `<root>/<ab>/<cd>/<key>`, so no directory grows too large
+pub struct FsBlobStore {
+    root: PathBuf,
+    capacity: u64,
+    used: u64,
+}
+
+impl FsBlobStore {
+    pub fn open(root: PathBuf, capacity: u64) -> Result<Self, Box<dyn Error>> {
+        fs::create_dir_all(&root)?;
+        let used = dir_size(&root);
+        Ok(FsBlobStore {
+            root,
+            capacity,
+            used,
+        })
+    }
+
+    fn path(&self, key: &str) -> Result<PathBuf, Box<dyn Error>> {
+        check_key(key)?;
+        Ok(self.root.join(&key[..2]).join(&key[2..4]).join(key))
+    }
+}
+
+impl BlobStore for FsBlobStore {
+    fn put(&mut self, key: &str, blob: &[u8]) -> Result<(), Box<dyn Error>> {
+        let path = self.path(key)?;
+        if path.exists() {
+            return Ok(());
+        }
+        check_capacity(self.used, self.capacity, blob.len())?;
+
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        // written aside first, so a crash never leaves a truncated blob under its key
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, blob)?;
+        fs::rename(tmp, path)?;
+        self.used += blob.len() as u64;
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+        Ok(fs::read(self.path(key)?)?)
+    }
+
+    fn contains(&self, key: &str) -> bool {
+        self.path(key).is_ok_and(|path| path.exists())
+    }
+
+    fn remove(&mut self, key: &str) -> bool {
+        let Ok(path) = self.path(key) else {
+            return false;
+        };
+        let size = fs::metadata(&path).map_or(0, |meta| meta.len());
+        if fs::remove_file(path).is_err() {
+            return false;
+        }
+        self.used = self.used.saturating_sub(size);
+        true
+    }
+
+    fn available(&self) -> u64 {
+        self.capacity.saturating_sub(self.used)
+    }
+}
+
+/// Keeps blobs in memory only, they are gone when the node stops
+pub struct MemoryBlobStore {
+    blobs: HashMap<String, Vec<u8>>,
+    capacity: u64,
+    used: u64,
+}
+
+impl MemoryBlobStore {
+    pub fn new(capacity: u64) -> Self {
+        MemoryBlobStore {
+            blobs: HashMap::new(),
+            capacity,
+            used: 0,
+        }
+    }
+}
+
+impl BlobStore for MemoryBlobStore {
+    fn put(&mut self, key: &str, blob: &[u8]) -> Result<(), Box<dyn Error>> {
+        check_key(key)?;
+        if self.blobs.contains_key(key) {
+            return Ok(());
+        }
+        check_capacity(self.used, self.capacity, blob.len())?;
+
+        self.used += blob.len() as u64;
+        self.blobs.insert(key.to_string(), blob.to_vec());
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+        check_key(key)?;
+        Ok(self.blobs.get(key).ok_or("blob not found")?.clone())
+    }
+
+    fn contains(&self, key: &str) -> bool {
+        self.blobs.contains_key(key)
+    }
+
+    fn remove(&mut self, key: &str) -> bool {
+        match self.blobs.remove(key) {
+            Some(blob) => {
+                self.used -= blob.len() as u64;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn available(&self) -> u64 {
+        self.capacity.saturating_sub(self.used)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha2::{Digest, Sha256};
+
+    fn key(blob: &[u8]) -> String {
+        hex::encode(Sha256::digest(blob))
+    }
+
+    fn temp_root() -> PathBuf {
+        std::env::temp_dir().join(format!("pastechain-blobs-{}", uuid::Uuid::new_v4()))
+    }
+
+    /// The behaviour both backends share
+    fn round_trip(store: &mut dyn BlobStore) {
+        let blob = b"chunk".to_vec();
+        let key = key(&blob);
+        assert!(!store.contains(&key));
+        assert!(store.get(&key).is_err());
+
+        store.put(&key, &blob).unwrap();
+        assert!(store.contains(&key));
+        assert_eq!(store.get(&key).unwrap(), blob);
+        assert_eq!(store.available(), 8 - blob.len() as u64);
+
+        // storing it again neither fails nor counts twice
+        store.put(&key, &blob).unwrap();
+        assert_eq!(store.available(), 8 - blob.len() as u64);
+
+        let other = b"too big".to_vec();
+        assert!(store.put(&self::key(&other), &other).is_err());
+        assert!(store.put("../../etc/passwd", &blob).is_err());
+
+        assert!(store.remove(&key));
+        assert!(!store.remove(&key));
+        assert!(!store.contains(&key));
+        assert_eq!(store.available(), 8);
+    }
+
+    #[test]
+    fn memory_store_round_trip() {
+        round_trip(&mut MemoryBlobStore::new(8));
+    }
+
+    #[test]
+    fn fs_store_round_trip() {
+        let root = temp_root();
+        round_trip(&mut FsBlobStore::open(root.clone(), 8).unwrap());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn fs_store_counts_existing_blobs_on_open() {
+        let root = temp_root();
+        let blob = b"chunk".to_vec();
+        FsBlobStore::open(root.clone(), 8)
+            .unwrap()
+            .put(&key(&blob), &blob)
+            .unwrap();
+
+        let store = FsBlobStore::open(root.clone(), 8).unwrap();
+        assert!(store.contains(&key(&blob)));
+        assert_eq!(store.available(), 8 - blob.len() as u64);
+        fs::remove_dir_all(root).unwrap();
+    }
+}
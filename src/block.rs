@@ -1,17 +1,99 @@
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
+use std::fmt;
 
-use crate::transaction::{MonetaryTx, StorageTx};
+use crate::transaction::{CapacityTx, DealTx, LifecycleTx, MonetaryTx, StorageTx, TransferTx};
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct Block {
     pub previous_hash: Option<String>,
     pub mtx: Option<MonetaryTx>,
     pub stx: Option<StorageTx>,
+    #[serde(default)]
+    pub ltx: Option<LifecycleTx>,
+    #[serde(default)]
+    pub ctx: Option<CapacityTx>,
+    #[serde(default)]
+    pub dtx: Option<DealTx>,
+    #[serde(default)]
+    pub ttx: Option<TransferTx>,
     pub hash: String,
 }
 
+/// The transaction a block carries, every block but the genesis carries exactly one. It only lives
+/// until it is moved into its block, so its size does not matter.
+#[allow(clippy::large_enum_variant)]
+pub enum Tx {
+    Storage(StorageTx),
+    Monetary(MonetaryTx),
+    Lifecycle(LifecycleTx),
+    Capacity(CapacityTx),
+    Deal(DealTx),
+    Transfer(TransferTx),
+}
+
+macro_rules! tx_from {
+    ($($variant:ident($ty:ty)),* $(,)?) => {
+        $(impl From<$ty> for Tx {
+            fn from(tx: $ty) -> Self {
+                Tx::$variant(tx)
+            }
+        })*
+    };
+}
+
+tx_from!(
+    Storage(StorageTx),
+    Monetary(MonetaryTx),
+    Lifecycle(LifecycleTx),
+    Capacity(CapacityTx),
+    Deal(DealTx),
+    Transfer(TransferTx),
+);
+
+impl fmt::Display for Tx {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Tx::Storage(stx) => write!(f, "storage of {} by {}", stx.request_id, stx.miner_id),
+            Tx::Monetary(mtx) => write!(f, "payment of {} to {}", mtx.amount, mtx.node_id),
+            Tx::Lifecycle(ltx) => write!(f, "{:?} of {}", ltx.action, ltx.request_id),
+            Tx::Capacity(ctx) => write!(
+                f,
+                "offer of {} bytes by {} at {} per MiB and epoch",
+                ctx.capacity, ctx.miner_id, ctx.price
+            ),
+            Tx::Deal(dtx) => write!(
+                f,
+                "deal of {} with {} for {}",
+                dtx.client_id, dtx.miner_id, dtx.request_id
+            ),
+            Tx::Transfer(ttx) => write!(
+                f,
+                "transfer of {} from {} to {}",
+                ttx.amount, ttx.from, ttx.to
+            ),
+        }
+    }
+}
+
 impl Block {
+    /// Hashed block on top of `previous_hash` carrying `tx`
+    pub fn new(previous_hash: &str, tx: Tx) -> Self {
+        let mut block = Block {
+            previous_hash: Some(previous_hash.to_string()),
+            ..Default::default()
+        };
+        match tx {
+            Tx::Storage(stx) => block.stx = Some(stx),
+            Tx::Monetary(mtx) => block.mtx = Some(mtx),
+            Tx::Lifecycle(ltx) => block.ltx = Some(ltx),
+            Tx::Capacity(ctx) => block.ctx = Some(ctx),
+            Tx::Deal(dtx) => block.dtx = Some(dtx),
+            Tx::Transfer(ttx) => block.ttx = Some(ttx),
+        }
+        block.calculate_hash()
+    }
+
     pub fn calculate_hash(mut self) -> Self {
         let prv_hash = match &self.previous_hash {
             Some(x) => x,
@@ -28,7 +110,27 @@ impl Block {
             None => "",
         };
 
-        let data = format!("{}{}{}", prv_hash, stx, mtx);
+        let ltx = match &self.ltx {
+            Some(x) => &format!("{:?}", x),
+            None => "",
+        };
+
+        let ctx = match &self.ctx {
+            Some(x) => &format!("{:?}", x),
+            None => "",
+        };
+
+        let dtx = match &self.dtx {
+            Some(x) => &format!("{:?}", x),
+            None => "",
+        };
+
+        let ttx = match &self.ttx {
+            Some(x) => &format!("{:?}", x),
+            None => "",
+        };
+
+        let data = format!("{}{}{}{}{}{}{}", prv_hash, stx, mtx, ltx, ctx, dtx, ttx);
         let hash = format!("{:x}", Sha256::digest(data.as_bytes()));
 
         self.hash = hash;
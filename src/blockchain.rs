@@ -1,13 +1,52 @@
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::{collections::HashMap, error::Error};
 
-use crate::block::Block;
+use crate::block::{Block, Tx};
+use crate::erasure::shard_request_id;
+use crate::node::Node;
+use crate::randomized_election::is_elected;
+use crate::transaction::{
+    CapacityTx, DealTx, LifecycleAction, LifecycleTx, MonetaryTx, StorageTx, TransferTx,
+};
+
+/// Number of distinct miners holding a file when the uploader does not ask for more (`k` in spec.md)
+pub const DEFAULT_REPLICATION_FACTOR: usize = 3;
+
+/// Fixed price a retrieving node pays a holder for every chunk it serves
+pub const RETRIEVAL_FEE_PER_CHUNK: f64 = 0.001;
+
+/// Length of a storage deal in epochs when the uploader does not renew it. An epoch is one block,
+/// the only clock every node agrees on.
+pub const DEFAULT_DEAL_EPOCHS: u64 = 100;
+
+/// Price of keeping one replica for one epoch, locked in escrow when a deal is renewed
+pub const STORAGE_FEE_PER_EPOCH: f64 = 0.0001;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Blockchain {
     pub chain: Vec<Block>,
     pub stored: HashMap<String, Vec<String>>,
     pub balance: HashMap<String, f64>,
+    /// End epoch of every storage deal that has one
+    #[serde(default)]
+    pub deals: HashMap<String, u64>,
+    #[serde(default)]
+    pub escrow: HashMap<String, f64>,
+    /// Latest capacity declaration of every miner
+    #[serde(default)]
+    pub capacities: HashMap<String, CapacityTx>,
+    /// Market deals recorded for every file, one per miner that agreed to store it
+    #[serde(default)]
+    pub market_deals: HashMap<String, Vec<DealTx>>,
+    /// Number of transfers every account sent
+    #[serde(default)]
+    pub nonces: HashMap<String, u64>,
+    /// Escrow paid out to every account, taken back when the chain is replaced
+    #[serde(default)]
+    pub released: HashMap<String, f64>,
+    /// Bumped whenever a block is added or a reward paid, so the state is only saved when it changed
+    #[serde(skip)]
+    generation: u64,
 }
 
 impl Blockchain {
@@ -16,62 +55,494 @@ impl Blockchain {
             chain: vec![],
             stored: HashMap::new(),
             balance: HashMap::new(),
+            deals: HashMap::new(),
+            escrow: HashMap::new(),
+            capacities: HashMap::new(),
+            market_deals: HashMap::new(),
+            nonces: HashMap::new(),
+            released: HashMap::new(),
+            generation: 0,
         };
 
-        blockchain.chain.push(
-            Block {
-                previous_hash: None,
-                mtx: None,
-                stx: None,
-                hash: "".to_string(),
-            }
-            .calculate_hash(),
-        );
+        blockchain.chain.push(Block::default().calculate_hash());
 
         blockchain
     }
 
-    pub fn search_transaction(&self, id: &str) -> bool {
-        let mut found: bool = false;
-        for block in &self.chain {
-            if let Some(tx) = &block.stx {
-                found |= tx.request_id == id;
+    /// Returns the first storage transaction committed for `id`, which fixes the file hash and size
+    pub fn find_storage_tx(&self, id: &str) -> Option<&StorageTx> {
+        self.chain
+            .iter()
+            .filter_map(|block| block.stx.as_ref())
+            .find(|tx| tx.request_id == id)
+    }
+
+    /// Storage transactions of every shard of an erasure-coded file, one per shard
+    pub fn shards_of(&self, file_id: &str) -> Vec<&StorageTx> {
+        let mut shards: Vec<&StorageTx> = vec![];
+        for stx in self.chain.iter().filter_map(|block| block.stx.as_ref()) {
+            if let Some(shard) = &stx.shard
+                && shard.file_id == file_id
+                && !shards.iter().any(|s| s.request_id == stx.request_id)
+            {
+                shards.push(stx);
             }
         }
+        shards
+    }
+
+    pub fn holders(&self, id: &str) -> &[String] {
+        self.stored.get(id).map(Vec::as_slice).unwrap_or_default()
+    }
+
+    pub fn epoch(&self) -> u64 {
+        self.chain.len() as u64
+    }
+
+    /// A file whose deal expired or was deleted is committed on-chain but no longer held by anyone
+    pub fn has_ended(&self, id: &str) -> bool {
+        self.find_storage_tx(id).is_some() && !self.stored.contains_key(id)
+    }
+
+    /// Storage transactions `node_id` mined for files whose deal has since ended
+    pub fn ended_deals(&self, node_id: &str) -> Vec<&StorageTx> {
+        self.chain
+            .iter()
+            .filter_map(|block| block.stx.as_ref())
+            .filter(|stx| stx.miner_id == node_id && self.has_ended(&stx.request_id))
+            .collect()
+    }
 
-        return found;
+    /// Bytes of the files `miner` currently holds
+    pub fn used_by(&self, miner: &str) -> u64 {
+        self.stored
+            .iter()
+            .filter(|(_, holders)| holders.iter().any(|id| id == miner))
+            .filter_map(|(id, _)| self.find_storage_tx(id))
+            .map(|stx| stx.file_size as u64)
+            .sum()
+    }
+
+    /// Declared capacity `miner` has left, nothing for miners that never declared any
+    pub fn free_space(&self, miner: &str) -> u64 {
+        self.capacities
+            .get(miner)
+            .map_or(0, |ctx| ctx.capacity.saturating_sub(self.used_by(miner)))
+    }
+
+    /// Free share of the capacity of `miner` relative to the emptiest declared miner, 1.0 for the
+    /// miner with the most room
+    pub fn room(&self, miner: &str) -> f64 {
+        let share = |id: &str| match self.capacities.get(id) {
+            Some(ctx) if ctx.capacity > 0 => self.free_space(id) as f64 / ctx.capacity as f64,
+            _ => 0.0,
+        };
+        let most = self
+            .capacities
+            .keys()
+            .map(|id| share(id))
+            .fold(0.0, f64::max);
+        if most > 0.0 { share(miner) / most } else { 0.0 }
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Credits a reward earned off-chain, such as for a verified proof
+    pub fn reward(&mut self, account: &str, amount: f64) {
+        *self.balance.entry(account.to_string()).or_insert(0.0) += amount;
+        self.generation += 1;
     }
 
     pub fn add_block(&mut self, block: Block) {
+        self.generation += 1;
+        if let Some(stx) = &block.stx {
+            self.apply_storage_tx(stx);
+            self.apply_storage_fee(stx, 1.0);
+        }
+        if let Some(mtx) = &block.mtx {
+            self.apply_monetary_tx(mtx, 1.0);
+        }
+        if let Some(ltx) = &block.ltx {
+            self.apply_lifecycle_tx(ltx, 1.0);
+        }
+        if let Some(ctx) = &block.ctx {
+            self.capacities.insert(ctx.miner_id.clone(), ctx.clone());
+        }
+        if let Some(dtx) = &block.dtx {
+            self.apply_deal_tx(dtx, 1.0);
+        }
+        if let Some(ttx) = &block.ttx {
+            self.apply_transfer_tx(ttx, 1.0);
+        }
         self.chain.push(block);
+        self.release_escrow();
+
+        // holders of expired deals are released, so they are neither challenged nor penalised
+        let epoch = self.epoch();
+        let deals = &self.deals;
+        self.stored
+            .retain(|id, _| deals.get(id).is_none_or(|end| *end > epoch));
+    }
+
+    /// Pays every file's holders their share of its escrow for the epoch, and whatever is left
+    /// once the deal ends. The escrow of a deleted file goes back to its owner.
+    fn release_escrow(&mut self) {
+        let epoch = self.epoch();
+        let ids: Vec<String> = self.escrow.keys().cloned().collect();
+        for id in ids {
+            // market deals are paid for before the file is stored, their escrow waits until it is
+            let Some(end) = self.deals.get(&id).copied() else {
+                continue;
+            };
+            let escrow = self.escrow[&id];
+            let holders = self.holders(&id).to_vec();
+            let payouts = if holders.is_empty() {
+                let Some(owner) = self.find_storage_tx(&id).map(|stx| stx.owner.clone()) else {
+                    continue;
+                };
+                vec![(owner, escrow)]
+            } else {
+                // the share of this epoch, split between the holders
+                let share = escrow / (end.saturating_sub(epoch) + 1) as f64 / holders.len() as f64;
+                holders.into_iter().map(|holder| (holder, share)).collect()
+            };
+            for (account, amount) in payouts {
+                *self.balance.entry(account.clone()).or_insert(0.0) += amount;
+                *self.released.entry(account).or_insert(0.0) += amount;
+                *self.escrow.get_mut(&id).unwrap() -= amount;
+            }
+            if self.holders(&id).is_empty() || epoch >= end {
+                self.escrow.remove(&id);
+            }
+        }
+    }
+
+    /// Pays the miner of a replica the fee its owner offered. `sign` is -1.0 to give the fee back
+    /// when the block leaves the chain.
+    fn apply_storage_fee(&mut self, stx: &StorageTx, sign: f64) {
+        if stx.fee != 0.0 {
+            *self.balance.entry(stx.owner.clone()).or_insert(0.0) -= sign * stx.fee;
+            *self.balance.entry(stx.miner_id.clone()).or_insert(0.0) += sign * stx.fee;
+        }
+    }
+
+    /// Locks the escrow of a renewal or forgets a deleted file. `sign` is -1.0 to give the escrow
+    /// back when the block leaves the chain.
+    fn apply_lifecycle_tx(&mut self, ltx: &LifecycleTx, sign: f64) {
+        match &ltx.action {
+            LifecycleAction::Renew { end_epoch, escrow } => {
+                if sign > 0.0 {
+                    self.deals.insert(ltx.request_id.clone(), *end_epoch);
+                    *self.escrow.entry(ltx.request_id.clone()).or_insert(0.0) += escrow;
+                }
+                *self.balance.entry(ltx.owner.clone()).or_insert(0.0) -= sign * escrow;
+            }
+            LifecycleAction::Delete => {
+                if sign > 0.0 {
+                    self.stored.remove(&ltx.request_id);
+                }
+            }
+        }
+    }
+
+    /// Moves the amount to `node_id`, taking it from the payer of the receipt if there is one.
+    /// `sign` is -1.0 to undo the transfer when the block leaves the chain.
+    fn apply_monetary_tx(&mut self, mtx: &MonetaryTx, sign: f64) {
+        *self.balance.entry(mtx.node_id.clone()).or_insert(0.0) += sign * mtx.amount;
+        if let Some(receipt) = &mtx.receipt {
+            *self.balance.entry(receipt.payer.clone()).or_insert(0.0) -= sign * mtx.amount;
+        }
+    }
+
+    /// Moves the amount between the accounts. `sign` is -1.0 to undo the transfer when the block
+    /// leaves the chain.
+    fn apply_transfer_tx(&mut self, ttx: &TransferTx, sign: f64) {
+        if sign > 0.0 {
+            self.nonces.insert(ttx.from.clone(), ttx.nonce);
+        }
+        *self.balance.entry(ttx.from.clone()).or_insert(0.0) -= sign * ttx.amount;
+        *self.balance.entry(ttx.to.clone()).or_insert(0.0) += sign * ttx.amount;
+    }
+
+    pub fn next_nonce(&self, account: &str) -> u64 {
+        self.nonces.get(account).copied().unwrap_or(0) + 1
     }
 
-    pub fn _verify_and_add(&self, _blk: &Block) -> bool {
-        return true;
-        // TODO: um.. this is probably the toughest part, will see..
-        // Things to verify:
-        // balances
-        // signatures
+    pub fn is_settled(&self, nonce: &str) -> bool {
+        self.chain
+            .iter()
+            .filter_map(|block| block.mtx.as_ref()?.receipt.as_ref())
+            .any(|receipt| receipt.nonce == nonce)
+    }
+
+    /// Records the deal and moves its price from the client into escrow. `sign` is -1.0 to give
+    /// the price back when the block leaves the chain.
+    fn apply_deal_tx(&mut self, dtx: &DealTx, sign: f64) {
+        if sign > 0.0 {
+            self.market_deals
+                .entry(dtx.request_id.clone())
+                .or_default()
+                .push(dtx.clone());
+            *self.escrow.entry(dtx.request_id.clone()).or_insert(0.0) += dtx.amount();
+        }
+        *self.balance.entry(dtx.client_id.clone()).or_insert(0.0) -= sign * dtx.amount();
+    }
+
+    fn apply_storage_tx(&mut self, stx: &StorageTx) {
+        let holders = self.stored.entry(stx.request_id.clone()).or_default();
+        holders.retain(|id| !stx.replaces.contains(id));
+        if !holders.contains(&stx.miner_id) {
+            holders.push(stx.miner_id.clone());
+        }
+        if let Some(end_epoch) = stx.end_epoch {
+            self.deals
+                .entry(stx.request_id.clone())
+                .or_insert(end_epoch);
+        }
+    }
+
+    /// Replication target of a stored file, fixed by its first storage transaction
+    pub fn replicas(&self, id: &str) -> usize {
+        self.find_storage_tx(id)
+            .map_or(DEFAULT_REPLICATION_FACTOR, |stx| stx.replicas)
+    }
+
+    /// Checks that `blk` extends the current tip without breaking the storage mapping
+    pub fn verify_block(&self, blk: &Block) -> Result<(), Box<dyn Error>> {
+        if blk.previous_hash.as_ref() != self.chain.last().map(|b| &b.hash) {
+            return Err("block does not extend the chain tip".into());
+        }
+        if blk.clone().calculate_hash().hash != blk.hash {
+            return Err("block hash mismatch".into());
+        }
+
+        if let Some(stx) = &blk.stx {
+            if stx.replicas == 0 {
+                return Err("replication factor must be at least 1".into());
+            }
+            if let Some(first) = self.find_storage_tx(&stx.request_id)
+                && (first.replicas != stx.replicas
+                    || first.file_hash != stx.file_hash
+                    || first.chunks != stx.chunks
+                    || first.merkle_root != stx.merkle_root
+                    || first.acl.as_ref().map(|acl| &acl.signature)
+                        != stx.acl.as_ref().map(|acl| &acl.signature)
+                    || first.owner != stx.owner
+                    || first.end_epoch != stx.end_epoch)
+            {
+                return Err("storage tx does not match the original request".into());
+            }
+            if self.has_ended(&stx.request_id) {
+                return Err("storage deal has ended".into());
+            }
+            if stx.end_epoch.is_some_and(|end| end <= self.epoch()) {
+                return Err("storage deal ends before it starts".into());
+            }
+            if let Some(acl) = &stx.acl
+                && (acl.request_id != stx.request_id || !acl.verify()?)
+            {
+                return Err("invalid access list".into());
+            }
+            if !stx.fee.is_finite() || stx.fee < 0.0 || !stx.verify_fee()? {
+                return Err("fee is not signed by the owner".into());
+            }
+
+            if let Some(shard) = &stx.shard {
+                if stx.request_id != shard_request_id(&shard.file_id, shard.index)
+                    || shard.index >= shard.data_shards + shard.parity_shards
+                {
+                    return Err("malformed shard".into());
+                }
+                // shards of one file must agree on how it was split
+                if self
+                    .shards_of(&shard.file_id)
+                    .iter()
+                    .filter_map(|other| other.shard.as_ref())
+                    .any(|other| {
+                        other.data_shards != shard.data_shards
+                            || other.parity_shards != shard.parity_shards
+                    })
+                {
+                    return Err("shard counts do not match the other shards of the file".into());
+                }
+                // every shard of a file must live on a different miner
+                if self
+                    .shards_of(&shard.file_id)
+                    .iter()
+                    .any(|other| self.holders(&other.request_id).contains(&stx.miner_id))
+                {
+                    return Err("miner already stores a shard of this file".into());
+                }
+            }
+
+            // files sold on the market may only be stored by the miners that signed a deal for them
+            if let Some(deals) = self.market_deals.get(&stx.request_id)
+                && !deals.iter().any(|dtx| dtx.miner_id == stx.miner_id)
+            {
+                return Err("miner has no deal for this file".into());
+            }
+            if stx.file_size as u64 > self.free_space(&stx.miner_id) {
+                return Err("miner has not declared enough free capacity".into());
+            }
+
+            let holders = self.holders(&stx.request_id);
+            if holders.contains(&stx.miner_id) {
+                return Err("miner already stores this file".into());
+            }
+            if stx.replaces.iter().any(|id| !holders.contains(id)) {
+                return Err("replaced node is not a holder".into());
+            }
+            // holders are only ever replaced on the word of another holder that signed the repair
+            if let Some(repair) = &stx.repair
+                && (repair.request_id != stx.request_id
+                    || repair.dropped != stx.replaces
+                    || !repair.verify(self)?)
+            {
+                return Err("invalid repair".into());
+            }
+            if stx.repair.is_none() && !stx.replaces.is_empty() {
+                return Err("replaced holders without a signed repair".into());
+            }
+            let remaining = holders
+                .iter()
+                .filter(|id| !stx.replaces.contains(id))
+                .count();
+            if remaining >= stx.replicas {
+                return Err("file already has k replicas".into());
+            }
+        }
+
+        if let Some(mtx) = &blk.mtx {
+            // coins only move on-chain as settlement of a signed retrieval receipt
+            let receipt = mtx.receipt.as_ref().ok_or("monetary tx without receipt")?;
+            if !receipt.verify()? {
+                return Err("invalid receipt signature".into());
+            }
+            if receipt.payee != mtx.node_id || receipt.amount != mtx.amount {
+                return Err("monetary tx does not match its receipt".into());
+            }
+            if receipt.amount != receipt.chunk_hashes.len() as f64 * RETRIEVAL_FEE_PER_CHUNK {
+                return Err("receipt amount does not match the fixed fee".into());
+            }
+            let stx = self
+                .find_storage_tx(&receipt.request_id)
+                .ok_or("receipt for unknown file")?;
+            if receipt
+                .chunk_hashes
+                .iter()
+                .any(|hash| !stx.chunks.contains(hash))
+            {
+                return Err("receipt lists chunks of another file".into());
+            }
+            if self.is_settled(&receipt.nonce) {
+                return Err("receipt already settled".into());
+            }
+        }
+
+        if let Some(ltx) = &blk.ltx {
+            ltx.check(self)?;
+        }
+        if let Some(ctx) = &blk.ctx {
+            ctx.check(self)?;
+        }
+        if let Some(dtx) = &blk.dtx {
+            dtx.check(self)?;
+        }
+        if let Some(ttx) = &blk.ttx {
+            ttx.check(self)?;
+        }
+
+        // TODO: still to verify:
         // random selection according own seed
-        // exsisting mappings should not change
-        // Extras:
-        // instead of k = 1, use k > 1 and verify the PoST by majority votes
+        Ok(())
+    }
+
+    /// Mines a block carrying `tx` on top of the chain, if it is valid and this node is elected
+    /// to propose it
+    pub fn propose_block(
+        &mut self,
+        node: &Node,
+        tx: impl Into<Tx>,
+        total_nodes: usize,
+    ) -> Result<Block, Box<dyn Error>> {
+        let tx = tx.into();
+        let summary = tx.to_string();
+        let block = Block::new(&self.chain.last().unwrap().hash, tx);
+
+        self.verify_block(&block)?;
+
+        if !is_elected(&node.id, &block.hash, total_nodes as u64) {
+            return Err("Not eligible to propose a block".into());
+        }
+
+        self.add_block(block.clone());
+        println!("[+] Mined the {summary}");
+
+        Ok(block)
     }
 
     pub fn verify(&self) -> bool {
-        // TODO: implement to verify a received blockchain is valid or not
-        return true;
+        // replay every block on top of a fresh genesis so the storage mapping is rebuilt as we go
+        let mut replay = Blockchain::new_with_genesis_block();
+        if self.chain.first().map(|b| &b.hash) != replay.chain.first().map(|b| &b.hash) {
+            return false;
+        }
+
+        for blk in self.chain.iter().skip(1) {
+            if replay.verify_block(blk).is_err() {
+                return false;
+            }
+            replay.add_block(blk.clone());
+        }
+
+        true
+    }
+
+    /// Longest chain wins, ties are broken by the smaller tip hash so that forks converge
+    pub fn is_preferred_to(&self, other: &Blockchain) -> bool {
+        let longer = other.chain.len() < self.chain.len();
+        let tie_won = other.chain.len() == self.chain.len()
+            && self.chain.last().map(|b| &b.hash) < other.chain.last().map(|b| &b.hash);
+        longer || tie_won
     }
 
     pub fn update(&mut self, new_chain: &mut Blockchain) {
-        for (key, mut new_list) in new_chain.stored.drain() {
-            self.stored
-                .entry(key)
-                .or_insert_with(Vec::new)
-                .append(&mut new_list);
-        }
-        if self.chain.len() < new_chain.chain.len() {
-            self.chain = new_chain.chain.clone();
+        if !new_chain.is_preferred_to(self) {
+            return;
+        }
+
+        // the storage mapping is derived from the adopted chain, so replaced holders stay removed.
+        // balances also carry local proof rewards, so only the transfers of the old chain are undone
+        for (account, amount) in std::mem::take(&mut self.released) {
+            *self.balance.entry(account).or_insert(0.0) -= amount;
+        }
+        for block in std::mem::take(&mut self.chain) {
+            if let Some(stx) = &block.stx {
+                self.apply_storage_fee(stx, -1.0);
+            }
+            if let Some(mtx) = &block.mtx {
+                self.apply_monetary_tx(mtx, -1.0);
+            }
+            if let Some(ltx) = &block.ltx {
+                self.apply_lifecycle_tx(ltx, -1.0);
+            }
+            if let Some(dtx) = &block.dtx {
+                self.apply_deal_tx(dtx, -1.0);
+            }
+            if let Some(ttx) = &block.ttx {
+                self.apply_transfer_tx(ttx, -1.0);
+            }
+        }
+        self.stored.clear();
+        self.deals.clear();
+        self.escrow.clear();
+        self.capacities.clear();
+        self.market_deals.clear();
+        self.nonces.clear();
+        for block in new_chain.chain.drain(..) {
+            self.add_block(block);
         }
     }
 }
@@ -0,0 +1,64 @@
+use std::error::Error;
+
+use crate::blockchain::Blockchain;
+use crate::node::{Node, verify_signature};
+use crate::transaction::CapacityTx;
+
+impl CapacityTx {
+    fn signing_bytes(&self) -> Vec<u8> {
+        serde_json::to_vec(&(&self.miner_id, self.capacity, self.price, self.sequence))
+            .unwrap_or_default()
+    }
+
+    /// Declares `capacity` bytes at `price`, superseding the miner's previous declaration
+    pub fn new(
+        node: &Node,
+        blockchain: &Blockchain,
+        capacity: u64,
+        price: f64,
+    ) -> Result<Self, Box<dyn Error>> {
+        let mut ctx = CapacityTx {
+            miner_id: node.id.clone(),
+            capacity,
+            price,
+            sequence: blockchain
+                .capacities
+                .get(&node.id)
+                .map_or(0, |ctx| ctx.sequence + 1),
+            signature: vec![],
+            public_key: node.public_key.clone(),
+        };
+        ctx.signature = node.private_key.sign(&ctx.signing_bytes())?;
+
+        Ok(ctx)
+    }
+
+    pub fn verify(&self) -> Result<bool, Box<dyn Error>> {
+        verify_signature(
+            &self.miner_id,
+            &self.public_key,
+            &self.signing_bytes(),
+            &self.signature,
+        )
+    }
+
+    /// Checks the signature and that the declaration is newer than the one on-chain and still
+    /// covers what the miner holds
+    pub fn check(&self, blockchain: &Blockchain) -> Result<(), Box<dyn Error>> {
+        if !self.verify()? {
+            return Err("invalid capacity signature".into());
+        }
+        if let Some(current) = blockchain.capacities.get(&self.miner_id)
+            && self.sequence <= current.sequence
+        {
+            return Err("capacity declaration is outdated".into());
+        }
+        if self.capacity < blockchain.used_by(&self.miner_id) {
+            return Err("capacity is below what the miner already holds".into());
+        }
+        if !self.price.is_finite() || self.price < 0.0 {
+            return Err("invalid price".into());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,116 @@
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaChaRng;
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::error::Error;
+use std::sync::LazyLock;
+
+use crate::blob_store::BlobStore;
+use crate::blockchain::Blockchain;
+
+const MIN_CHUNK: usize = 2 * 1024;
+const MAX_CHUNK: usize = 64 * 1024;
+// 13 bits gives an average chunk of about 8 KiB past the minimum
+const BOUNDARY_MASK: u64 = (1 << 13) - 1;
+
+// the gear table must be identical on every node so that chunk boundaries agree
+static GEAR: LazyLock<[u64; 256]> = LazyLock::new(|| {
+    let mut rng = ChaChaRng::from_seed([42; 32]);
+    std::array::from_fn(|_| rng.random())
+});
+
+/// Cuts `data` at content-defined boundaries found with a gear rolling hash, so an insertion
+/// only changes the chunks around it
+pub fn split(data: &[u8]) -> Vec<&[u8]> {
+    let mut chunks = vec![];
+    let mut start = 0;
+    let mut hash: u64 = 0;
+
+    for (i, byte) in data.iter().enumerate() {
+        hash = (hash << 1).wrapping_add(GEAR[*byte as usize]);
+        let len = i + 1 - start;
+        if (len >= MIN_CHUNK && hash & BOUNDARY_MASK == 0) || len >= MAX_CHUNK {
+            chunks.push(&data[start..=i]);
+            start = i + 1;
+            hash = 0;
+        }
+    }
+    if start < data.len() {
+        chunks.push(&data[start..]);
+    }
+
+    chunks
+}
+
+pub fn chunk_hash(chunk: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(chunk))
+}
+
+/// Stores the chunks of `data` that are not in the store yet and returns the chunk list
+pub fn write_chunks(blobs: &mut dyn BlobStore, data: &[u8]) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut hashes = vec![];
+    for chunk in split(data) {
+        let hash = chunk_hash(chunk);
+        write_chunk(blobs, &hash, chunk)?;
+        hashes.push(hash);
+    }
+
+    Ok(hashes)
+}
+
+/// Stores a single chunk received from a peer after checking it against its hash
+pub fn write_chunk(
+    blobs: &mut dyn BlobStore,
+    hash: &str,
+    chunk: &[u8],
+) -> Result<(), Box<dyn Error>> {
+    if chunk_hash(chunk) != hash {
+        return Err("chunk does not match its hash".into());
+    }
+    blobs.put(hash, chunk)
+}
+
+pub fn read_chunks(blobs: &dyn BlobStore, hashes: &[String]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut data = vec![];
+    for hash in hashes {
+        data.extend(blobs.get(hash)?);
+    }
+    Ok(data)
+}
+
+/// Deletes the chunks of `hashes` that are not in `keep`, since files share chunks. Returns how
+/// many were removed.
+pub fn remove_chunks(
+    blobs: &mut dyn BlobStore,
+    hashes: &[String],
+    keep: &HashSet<String>,
+) -> usize {
+    hashes
+        .iter()
+        .filter(|hash| !keep.contains(*hash))
+        .filter(|hash| blobs.remove(hash))
+        .count()
+}
+
+/// `(index, hash)` of the chunks of the list that this node does not have yet, without duplicates
+pub fn missing_chunks(blobs: &dyn BlobStore, hashes: &[String]) -> Vec<(usize, String)> {
+    let mut missing: Vec<(usize, String)> = vec![];
+    for (index, hash) in hashes.iter().enumerate() {
+        if !blobs.contains(hash) && !missing.iter().any(|(_, h)| h == hash) {
+            missing.push((index, hash.clone()));
+        }
+    }
+    missing
+}
+
+/// Reassembles a locally stored file from the chunk list committed on-chain
+pub fn read_file(
+    blobs: &dyn BlobStore,
+    blockchain: &Blockchain,
+    request_id: &str,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let stx = blockchain
+        .find_storage_tx(request_id)
+        .ok_or("unknown request id")?;
+    read_chunks(blobs, &stx.chunks)
+}
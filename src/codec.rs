@@ -0,0 +1,94 @@
+use bincode::Options;
+use serde::{Serialize, de::DeserializeOwned};
+use std::borrow::Cow;
+use std::error::Error;
+
+/// Wire versions: 1 gossips JSON envelopes, 2 bincode ones with large payloads lz4 compressed
+pub const JSON_VERSION: u32 = 1;
+pub const BINARY_VERSION: u32 = 2;
+
+/// Newest wire version this node speaks
+pub const PROTOCOL_VERSION: u32 = BINARY_VERSION;
+
+/// Payloads larger than this are compressed, if that makes them smaller
+const COMPRESSION_THRESHOLD: usize = 4 * 1024;
+
+/// Nothing is decoded or inflated beyond this, so a small message cannot claim a huge allocation
+const MAX_PAYLOAD: usize = 64 << 20;
+
+const PLAIN: u8 = 0;
+const LZ4: u8 = 1;
+
+fn options() -> impl Options {
+    bincode::DefaultOptions::new().with_limit(MAX_PAYLOAD as u64)
+}
+
+/// Encodes with bincode behind a one byte flag telling whether the rest is lz4 compressed
+pub fn to_bytes<T: Serialize>(value: &T) -> Result<Vec<u8>, Box<dyn Error>> {
+    let bytes = options().serialize(value)?;
+    if bytes.len() > COMPRESSION_THRESHOLD {
+        let compressed = lz4_flex::compress_prepend_size(&bytes);
+        if compressed.len() < bytes.len() {
+            return Ok([&[LZ4][..], &compressed].concat());
+        }
+    }
+    Ok([&[PLAIN][..], &bytes].concat())
+}
+
+/// Whether the flag in front of `bytes` is one this node knows, newer nodes may add encodings
+pub fn is_known_encoding(bytes: &[u8]) -> bool {
+    matches!(bytes.first(), Some(&PLAIN) | Some(&LZ4))
+}
+
+pub fn from_bytes<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, Box<dyn Error>> {
+    let (flag, body) = bytes.split_first().ok_or("empty payload")?;
+    let body = match *flag {
+        PLAIN => Cow::Borrowed(body),
+        LZ4 => {
+            let size: [u8; 4] = body
+                .get(..4)
+                .and_then(|size| size.try_into().ok())
+                .ok_or("truncated payload")?;
+            if u32::from_le_bytes(size) as usize > MAX_PAYLOAD {
+                return Err("payload too large".into());
+            }
+            Cow::Owned(lz4_flex::decompress_size_prepended(body)?)
+        }
+        _ => return Err("unknown payload encoding".into()),
+    };
+    Ok(options().deserialize(&body)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn small_payloads_round_trip_plain() {
+        let value = ("small".to_string(), 42u64);
+        let bytes = to_bytes(&value).unwrap();
+        assert_eq!(bytes[0], PLAIN);
+        assert_eq!(from_bytes::<(String, u64)>(&bytes).unwrap(), value);
+    }
+
+    #[test]
+    fn large_payloads_round_trip_compressed() {
+        let value = vec![7u8; 4 * COMPRESSION_THRESHOLD];
+        let bytes = to_bytes(&value).unwrap();
+        assert_eq!(bytes[0], LZ4);
+        assert!(bytes.len() < value.len());
+        assert_eq!(from_bytes::<Vec<u8>>(&bytes).unwrap(), value);
+    }
+
+    #[test]
+    fn oversized_or_unknown_payloads_are_refused() {
+        let claimed = (MAX_PAYLOAD as u32 + 1).to_le_bytes();
+        let bytes = [&[LZ4][..], &claimed, &[0; 8]].concat();
+        assert!(from_bytes::<Vec<u8>>(&bytes).is_err());
+
+        assert!(from_bytes::<Vec<u8>>(&[LZ4, 1, 0]).is_err());
+        assert!(from_bytes::<Vec<u8>>(&[]).is_err());
+        assert!(!is_known_encoding(&[9, 0]));
+        assert!(from_bytes::<Vec<u8>>(&[9, 0]).is_err());
+    }
+}
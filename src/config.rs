@@ -0,0 +1,126 @@
+use libp2p::Multiaddr;
+use std::env;
+use std::path::PathBuf;
+
+use crate::network::Channel;
+
+/// Where a node keeps everything it writes, unless `PASTECHAIN_DATA_DIR` says otherwise
+const DEFAULT_DATA_DIR: &str = "data";
+
+/// Bytes of chunks a node agrees to keep, unless `PASTECHAIN_CAPACITY` says otherwise
+const DEFAULT_CAPACITY: u64 = 1 << 30;
+
+/// Price per MiB and epoch a node asks as a miner, unless `PASTECHAIN_PRICE` says otherwise
+const DEFAULT_PRICE: f64 = 0.01;
+
+/// Bytes of pending requests a node keeps, unless `PASTECHAIN_MEMPOOL_BYTES` says otherwise
+const DEFAULT_MEMPOOL_BYTES: usize = 8 << 20;
+
+/// Addresses a node listens on, unless `PASTECHAIN_LISTEN` says otherwise
+const DEFAULT_LISTEN: [&str; 2] = ["/ip4/0.0.0.0/udp/0/quic-v1", "/ip4/0.0.0.0/tcp/0"];
+
+pub struct Config {
+    pub data_dir: PathBuf,
+    pub capacity: u64,
+    pub price: f64,
+    /// Fee offered to each miner of our uploads, set with `PASTECHAIN_FEE`
+    pub fee: f64,
+    /// Size of the mempool, the lowest fees are dropped beyond it
+    pub mempool_bytes: usize,
+    /// Keeps chunks in memory only, set with `PASTECHAIN_BLOB_STORE=memory` for throwaway nodes
+    pub in_memory: bool,
+    /// Gossip topics to follow, `PASTECHAIN_TOPICS=blocks,proofs` makes a light node. Blocks are
+    /// always followed.
+    pub channels: Vec<Channel>,
+    /// Nodes to join the DHT through, `PASTECHAIN_BOOTSTRAP` lists their multiaddrs separated by
+    /// commas, each ending in `/p2p/<peer id>`
+    pub bootstrap: Vec<Multiaddr>,
+    /// Addresses to listen on, `PASTECHAIN_LISTEN` lists multiaddrs separated by commas to pin
+    /// ports or interfaces
+    pub listen: Vec<Multiaddr>,
+    /// Discovers peers on the local network, `PASTECHAIN_MDNS=off` for deployments that are not
+    pub mdns: bool,
+    /// Encrypts the node key on disk, read from `PASTECHAIN_PASSPHRASE`
+    pub passphrase: Option<String>,
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        Config {
+            data_dir: env::var("PASTECHAIN_DATA_DIR")
+                .map_or(PathBuf::from(DEFAULT_DATA_DIR), PathBuf::from),
+            capacity: env::var("PASTECHAIN_CAPACITY")
+                .ok()
+                .and_then(|capacity| capacity.parse().ok())
+                .unwrap_or(DEFAULT_CAPACITY),
+            price: env::var("PASTECHAIN_PRICE")
+                .ok()
+                .and_then(|price| price.parse().ok())
+                .unwrap_or(DEFAULT_PRICE),
+            fee: env::var("PASTECHAIN_FEE")
+                .ok()
+                .and_then(|fee| fee.parse().ok())
+                .unwrap_or(0.0),
+            mempool_bytes: env::var("PASTECHAIN_MEMPOOL_BYTES")
+                .ok()
+                .and_then(|bytes| bytes.parse().ok())
+                .unwrap_or(DEFAULT_MEMPOOL_BYTES),
+            in_memory: env::var("PASTECHAIN_BLOB_STORE").is_ok_and(|store| store == "memory"),
+            channels: env::var("PASTECHAIN_TOPICS").map_or(Channel::ALL.to_vec(), |topics| {
+                Channel::ALL
+                    .into_iter()
+                    .filter(|channel| {
+                        *channel == Channel::Blocks
+                            || topics.split(',').any(|name| name.trim() == channel.name())
+                    })
+                    .collect()
+            }),
+            bootstrap: env::var("PASTECHAIN_BOOTSTRAP")
+                .unwrap_or_default()
+                .split(',')
+                .filter(|address| !address.trim().is_empty())
+                .filter_map(|address| {
+                    address
+                        .trim()
+                        .parse()
+                        .inspect_err(|e| println!("[!!] Invalid bootstrap address {address}: {e}"))
+                        .ok()
+                })
+                .collect(),
+            listen: env::var("PASTECHAIN_LISTEN")
+                .unwrap_or_else(|_| DEFAULT_LISTEN.join(","))
+                .split(',')
+                .filter(|address| !address.trim().is_empty())
+                .filter_map(|address| {
+                    address
+                        .trim()
+                        .parse()
+                        .inspect_err(|e| println!("[!!] Invalid listen address {address}: {e}"))
+                        .ok()
+                })
+                .collect(),
+            mdns: env::var("PASTECHAIN_MDNS").map_or(true, |mdns| mdns != "off" && mdns != "0"),
+            passphrase: env::var("PASTECHAIN_PASSPHRASE").ok(),
+        }
+    }
+
+    pub fn key_file(&self) -> PathBuf {
+        self.data_dir.join("node.key")
+    }
+
+    pub fn wallet_file(&self) -> PathBuf {
+        self.data_dir.join("wallet.json")
+    }
+
+    pub fn blob_dir(&self) -> PathBuf {
+        self.data_dir.join("blobs")
+    }
+
+    pub fn download_dir(&self) -> PathBuf {
+        self.data_dir.join("downloads")
+    }
+
+    pub fn store_dir(&self) -> PathBuf {
+        self.data_dir.join("store")
+    }
+}
@@ -1,42 +1,92 @@
-use libp2p::{gossipsub, identity};
+use libp2p::identity;
 use serde::{Deserialize, Serialize};
 use std::error::Error;
 
-use crate::{network::MyBehaviour, node::Node};
+use crate::codec::{self, BINARY_VERSION, JSON_VERSION, PROTOCOL_VERSION};
+use crate::message::{Message, MessageRef};
+use crate::network::{Gossip, MyBehaviour};
+use crate::node::Node;
 
+/// Signed envelope around every gossiped message
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Data {
+    /// Wire version the envelope and its message are encoded with
+    pub version: u32,
+    /// Newest wire version the sender speaks, so peers know when they can switch to it
+    #[serde(default = "json_version")]
+    pub max_version: u32,
     pub node_id: String,
     pub data: Vec<u8>,
     pub signature: Vec<u8>,
     pub public_key: Vec<u8>,
 }
 
+fn json_version() -> u32 {
+    JSON_VERSION
+}
+
 impl Data {
-    pub fn broadcast<T: serde::Serialize>(
-        node: &Node,
-        data: T,
-        swarm: &mut libp2p::Swarm<MyBehaviour>,
-        topic: &gossipsub::IdentTopic,
-    ) -> Result<(), Box<dyn Error>> {
-        let data = serde_json::to_vec(&data)?;
-        let signature = node.private_key.sign(&data)?;
-        let data = Data {
+    fn seal(node: &Node, version: u32, data: Vec<u8>) -> Result<Self, Box<dyn Error>> {
+        Ok(Data {
+            version,
+            max_version: PROTOCOL_VERSION,
             node_id: node.id.clone(),
+            signature: node.private_key.sign(&data)?,
             data,
-            signature,
             public_key: node.public_key.clone(),
-        };
-        let data = serde_json::to_vec(&data)?;
+        })
+    }
 
-        swarm
+    /// Publishes the message on the topic of its class, and as JSON on the legacy topic while
+    /// version 1 peers are still around
+    pub fn broadcast<'a>(
+        node: &Node,
+        message: impl Into<MessageRef<'a>>,
+        swarm: &mut libp2p::Swarm<MyBehaviour>,
+        gossip: &Gossip,
+    ) -> Result<(), Box<dyn Error>> {
+        let message = message.into();
+        let data = Data::seal(node, BINARY_VERSION, codec::to_bytes(&message)?)?;
+        let published = swarm
             .behaviour_mut()
             .gossipsub
-            .publish(topic.clone(), data)?;
+            .publish(message.channel().topic(), codec::to_bytes(&data)?);
+
+        if let Some(legacy) = gossip.legacy_peers() {
+            let data = Data::seal(node, JSON_VERSION, message.to_json()?)?;
+            swarm
+                .behaviour_mut()
+                .gossipsub
+                .publish(legacy.clone(), serde_json::to_vec(&data)?)?;
+        }
 
+        published?;
         Ok(())
     }
 
+    /// Decodes a received envelope, JSON or binary, into the verified envelope and its message.
+    /// `None` for envelopes of a newer wire version than this node speaks, which are not the
+    /// sender's fault.
+    pub fn open(bytes: &[u8]) -> Result<Option<(Data, Message)>, Box<dyn Error>> {
+        // JSON envelopes are objects, binary ones start with the codec flag
+        let data = match bytes.first() {
+            Some(b'{') => serde_json::from_slice::<Data>(bytes)?,
+            _ if codec::is_known_encoding(bytes) => codec::from_bytes::<Data>(bytes)?,
+            _ => return Ok(None),
+        };
+        if !data.verify()? {
+            return Err("cannot verify received data".into());
+        }
+
+        let message = match data.version {
+            JSON_VERSION => Message::from_json(&data.data)?,
+            BINARY_VERSION => codec::from_bytes(&data.data)?,
+            version if version > PROTOCOL_VERSION => return Ok(None),
+            version => return Err(format!("unsupported protocol version {version}").into()),
+        };
+        Ok(Some((data, message)))
+    }
+
     pub fn verify(&self) -> Result<bool, Box<dyn Error>> {
         let public_key = identity::PublicKey::try_decode_protobuf(&self.public_key)?;
         let expected_id = identity::PeerId::from_public_key(&public_key).to_string();
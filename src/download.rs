@@ -0,0 +1,412 @@
+use libp2p::{PeerId, request_response::OutboundRequestId};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::{fs, path::PathBuf};
+
+use crate::blob_store::BlobStore;
+use crate::blockchain::RETRIEVAL_FEE_PER_CHUNK;
+use crate::chunking;
+use crate::mempool::MemPoolRequest;
+use crate::merkle;
+use crate::transaction::StorageTx;
+use crate::transfer::{self, ChunkRequest, ChunkResponse};
+
+/// Chunk requests kept in flight per holder, so neither side is flooded
+const SOURCE_WINDOW: usize = 2;
+
+/// Bad or timed out responses after which a holder is no longer asked
+const MAX_SOURCE_FAILURES: usize = 3;
+
+/// A download that ended, with the verified file or the reason it failed
+pub struct Finished {
+    pub request_id: String,
+    pub result: Result<Vec<u8>, Box<dyn Error>>,
+    /// Chunks each holder delivered and that passed verification, to pay them for
+    pub delivered: Vec<(PeerId, Vec<String>)>,
+    /// Holders that kept failing to serve their chunks
+    pub failed: Vec<PeerId>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    stx: StorageTx,
+    holders: Vec<String>,
+}
+
+struct Source {
+    peer: PeerId,
+    in_flight: usize,
+    failures: usize,
+    delivered: Vec<String>,
+}
+
+/// Retrieval of one stored file straight from its holders, chunk by chunk
+struct Download {
+    stx: StorageTx,
+    dir: PathBuf,
+    pending: VecDeque<usize>,
+    sources: Vec<Source>,
+}
+
+impl Download {
+    fn chunk_path(&self, index: usize) -> PathBuf {
+        self.dir.join(&self.stx.chunks[index])
+    }
+
+    fn is_complete(&self) -> bool {
+        (0..self.stx.chunks.len()).all(|index| self.chunk_path(index).exists())
+    }
+
+    /// Puts the chunks back together and checks the result against the committed file hash
+    fn assemble(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut data = vec![];
+        for index in 0..self.stx.chunks.len() {
+            data.extend(fs::read(self.chunk_path(index))?);
+        }
+
+        if format!("{:x}", Sha256::digest(&data)) != self.stx.file_hash {
+            return Err("downloaded file does not match its hash".into());
+        }
+        Ok(data)
+    }
+}
+
+/// Pulls files in parallel from several holders, verifying every chunk against the merkle root
+/// on-chain and moving chunks away from holders that fail or time out
+pub struct DownloadManager {
+    /// Partial downloads live here so they can be resumed after a restart
+    dir: PathBuf,
+    downloads: HashMap<String, Download>,
+    in_flight: HashMap<OutboundRequestId, (String, usize, PeerId)>,
+}
+
+impl DownloadManager {
+    /// Picks up the downloads left unfinished by a previous run
+    pub fn load(dir: PathBuf) -> Self {
+        let mut manager = DownloadManager {
+            dir,
+            downloads: HashMap::new(),
+            in_flight: HashMap::new(),
+        };
+
+        for entry in fs::read_dir(&manager.dir).into_iter().flatten().flatten() {
+            let manifest = fs::read(entry.path().join("manifest.json"))
+                .ok()
+                .and_then(|bytes| serde_json::from_slice::<Manifest>(&bytes).ok());
+            if let Some(manifest) = manifest {
+                println!("[#] Resuming download of {}", manifest.stx.request_id);
+                manager.start(&manifest.stx, &manifest.holders).ok();
+            }
+        }
+
+        manager
+    }
+
+    pub fn start(&mut self, stx: &StorageTx, holders: &[String]) -> Result<(), Box<dyn Error>> {
+        let sources: Vec<Source> = holders
+            .iter()
+            .filter_map(|id| id.parse().ok())
+            .map(|peer| Source {
+                peer,
+                in_flight: 0,
+                failures: 0,
+                delivered: vec![],
+            })
+            .collect();
+
+        // the request id names a directory, so only accept what our own ids look like
+        if !stx
+            .request_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+        {
+            return Err("invalid request id".into());
+        }
+        let dir = self.dir.join(&stx.request_id);
+        fs::create_dir_all(&dir)?;
+        let manifest = Manifest {
+            stx: stx.clone(),
+            holders: holders.to_vec(),
+        };
+        fs::write(dir.join("manifest.json"), serde_json::to_vec(&manifest)?)?;
+
+        let mut download = Download {
+            stx: stx.clone(),
+            dir,
+            pending: VecDeque::new(),
+            sources,
+        };
+        download.pending = (0..stx.chunks.len())
+            .filter(|index| !download.chunk_path(*index).exists())
+            .collect();
+
+        self.downloads.insert(stx.request_id.clone(), download);
+        Ok(())
+    }
+
+    /// Adds holders found outside the chain, such as DHT providers
+    pub fn add_sources(&mut self, request_id: &str, peers: impl IntoIterator<Item = PeerId>) {
+        let Some(download) = self.downloads.get_mut(request_id) else {
+            return;
+        };
+        for peer in peers {
+            if !download.sources.iter().any(|s| s.peer == peer) {
+                download.sources.push(Source {
+                    peer,
+                    in_flight: 0,
+                    failures: 0,
+                    delivered: vec![],
+                });
+            }
+        }
+    }
+
+    /// Gives up on a download nobody can serve, its chunks stay on disk for the next start
+    pub fn drop_sourceless(&mut self, request_id: &str) -> bool {
+        let sourceless = self
+            .downloads
+            .get(request_id)
+            .is_some_and(|download| download.sources.is_empty());
+        if sourceless {
+            self.downloads.remove(request_id);
+        }
+        sourceless
+    }
+
+    /// Stops a download we no longer need and forgets its chunks, late responses are ignored
+    pub fn cancel(&mut self, request_id: &str) {
+        if let Some(download) = self.downloads.remove(request_id) {
+            fs::remove_dir_all(&download.dir).ok();
+        }
+        self.in_flight
+            .retain(|_, (downloading, _, _)| downloading != request_id);
+    }
+
+    pub fn owns(&self, id: &OutboundRequestId) -> bool {
+        self.in_flight.contains_key(id)
+    }
+
+    /// Sends as many chunk requests as the holders' windows allow
+    pub fn poll(&mut self, transfer: &mut transfer::Behaviour) {
+        for (request_id, download) in self.downloads.iter_mut() {
+            while let Some(&index) = download.pending.front() {
+                let Some(source) = download
+                    .sources
+                    .iter_mut()
+                    .filter(|s| s.failures < MAX_SOURCE_FAILURES && s.in_flight < SOURCE_WINDOW)
+                    .min_by_key(|s| (s.failures, s.in_flight))
+                else {
+                    break;
+                };
+
+                download.pending.pop_front();
+                source.in_flight += 1;
+                let id = transfer.send_request(
+                    &source.peer,
+                    ChunkRequest {
+                        request_id: request_id.clone(),
+                        index,
+                        chunk_hash: download.stx.chunks[index].clone(),
+                        fee: RETRIEVAL_FEE_PER_CHUNK,
+                    },
+                );
+                self.in_flight
+                    .insert(id, (request_id.clone(), index, source.peer));
+            }
+        }
+    }
+
+    /// Handles a chunk response and returns the file once its download is complete
+    pub fn on_response(
+        &mut self,
+        id: OutboundRequestId,
+        response: ChunkResponse,
+    ) -> Option<Finished> {
+        let (request_id, index, peer) = self.in_flight.remove(&id)?;
+        let download = self.downloads.get_mut(&request_id)?;
+
+        let valid = response.index == index
+            && response.chunk.as_ref().is_some_and(|chunk| {
+                let leaf = chunking::chunk_hash(chunk);
+                leaf == download.stx.chunks[index]
+                    && merkle::verify(&download.stx.merkle_root, &leaf, index, &response.proof)
+            });
+        let stored = valid
+            && response
+                .chunk
+                .is_some_and(|chunk| fs::write(download.chunk_path(index), chunk).is_ok());
+
+        if let Some(source) = download.sources.iter_mut().find(|s| s.peer == peer) {
+            source.in_flight = source.in_flight.saturating_sub(1);
+            if stored {
+                source.delivered.push(download.stx.chunks[index].clone());
+            } else {
+                source.failures += 1;
+            }
+        }
+        if !stored {
+            download.pending.push_back(index);
+        }
+
+        self.finish(&request_id)
+    }
+
+    /// A timed out or failed request counts against the holder and its chunk is retried elsewhere
+    pub fn on_failure(&mut self, id: OutboundRequestId) -> Option<Finished> {
+        let (request_id, index, peer) = self.in_flight.remove(&id)?;
+        let download = self.downloads.get_mut(&request_id)?;
+        if let Some(source) = download.sources.iter_mut().find(|s| s.peer == peer) {
+            source.in_flight = source.in_flight.saturating_sub(1);
+            source.failures += 1;
+        }
+        download.pending.push_back(index);
+
+        self.finish(&request_id)
+    }
+
+    fn finish(&mut self, request_id: &str) -> Option<Finished> {
+        let download = self.downloads.get(request_id)?;
+        let exhausted = download
+            .sources
+            .iter()
+            .all(|s| s.failures >= MAX_SOURCE_FAILURES && s.in_flight == 0);
+
+        let result = if download.is_complete() {
+            download.assemble()
+        } else if exhausted {
+            Err("every holder failed to serve the file".into())
+        } else {
+            return None;
+        };
+
+        // a download every holder gave up on keeps its chunks on disk and resumes on the next start
+        let download = self.downloads.remove(request_id)?;
+        if !exhausted {
+            fs::remove_dir_all(&download.dir).ok();
+        }
+        let failed = download
+            .sources
+            .iter()
+            .filter(|s| s.failures >= MAX_SOURCE_FAILURES)
+            .map(|s| s.peer)
+            .collect();
+        Some(Finished {
+            request_id: request_id.to_string(),
+            result,
+            delivered: download
+                .sources
+                .into_iter()
+                .filter(|s| !s.delivered.is_empty())
+                .map(|s| (s.peer, s.delivered))
+                .collect(),
+            failed,
+        })
+    }
+}
+
+/// A request this node was elected to store, its chunks still coming from the uploader
+struct Fetch {
+    request: MemPoolRequest,
+    source: PeerId,
+    pending: VecDeque<(usize, String)>,
+    in_flight: usize,
+}
+
+/// How a fetch ended
+pub enum Fetched {
+    /// Every chunk is stored, so the request can be mined
+    Complete(MemPoolRequest),
+    /// The uploader failed to serve a chunk or it could not be stored
+    Abandoned(MemPoolRequest),
+}
+
+/// Pulls the files this node is about to mine from their uploaders, keeping as few requests in
+/// flight per uploader as a download does per holder
+#[derive(Default)]
+pub struct FetchManager {
+    fetches: HashMap<String, Fetch>,
+    /// Request id and chunk hash asked for by every request in flight
+    in_flight: HashMap<OutboundRequestId, (String, String)>,
+}
+
+impl FetchManager {
+    pub fn start(
+        &mut self,
+        source: PeerId,
+        request: MemPoolRequest,
+        missing: Vec<(usize, String)>,
+    ) {
+        let fetch = Fetch {
+            source,
+            pending: missing.into(),
+            in_flight: 0,
+            request,
+        };
+        self.fetches.insert(fetch.request.request_id.clone(), fetch);
+    }
+
+    pub fn owns(&self, id: &OutboundRequestId) -> bool {
+        self.in_flight.contains_key(id)
+    }
+
+    /// Sends as many chunk requests as the uploaders' windows allow
+    pub fn poll(&mut self, transfer: &mut transfer::Behaviour) {
+        for (request_id, fetch) in self.fetches.iter_mut() {
+            while fetch.in_flight < SOURCE_WINDOW
+                && let Some((index, chunk_hash)) = fetch.pending.pop_front()
+            {
+                fetch.in_flight += 1;
+                let id = transfer.send_request(
+                    &fetch.source,
+                    ChunkRequest {
+                        request_id: request_id.clone(),
+                        index,
+                        chunk_hash: chunk_hash.clone(),
+                        fee: 0.0,
+                    },
+                );
+                self.in_flight.insert(id, (request_id.clone(), chunk_hash));
+            }
+        }
+    }
+
+    /// Stores a fetched chunk and returns the request once the fetch is over
+    pub fn on_response(
+        &mut self,
+        id: OutboundRequestId,
+        response: ChunkResponse,
+        blobs: &mut dyn BlobStore,
+    ) -> Option<Fetched> {
+        let (request_id, chunk_hash) = self.in_flight.remove(&id)?;
+        let fetch = self.fetches.get_mut(&request_id)?;
+        fetch.in_flight -= 1;
+
+        let stored = response.chunk_hash == chunk_hash
+            && response
+                .chunk
+                .is_some_and(|chunk| chunking::write_chunk(blobs, &chunk_hash, &chunk).is_ok());
+        if !stored {
+            return self.abandon(&request_id);
+        }
+        if fetch.pending.is_empty() && fetch.in_flight == 0 {
+            let fetch = self.fetches.remove(&request_id)?;
+            return Some(Fetched::Complete(fetch.request));
+        }
+        None
+    }
+
+    pub fn on_failure(&mut self, id: OutboundRequestId) -> Option<Fetched> {
+        let (request_id, _) = self.in_flight.remove(&id)?;
+        self.abandon(&request_id)
+    }
+
+    /// Responses still on their way for an abandoned fetch are ignored
+    fn abandon(&mut self, request_id: &str) -> Option<Fetched> {
+        self.in_flight
+            .retain(|_, (fetched, _)| fetched != request_id);
+        let fetch = self.fetches.remove(request_id)?;
+        Some(Fetched::Abandoned(fetch.request))
+    }
+}
@@ -0,0 +1,49 @@
+use chacha20poly1305::{
+    ChaCha20Poly1305, Key, KeyInit, Nonce,
+    aead::{Aead, AeadCore, OsRng},
+};
+use sha2::{Digest, Sha256};
+use std::error::Error;
+
+use crate::node::Node;
+
+const NONCE_LEN: usize = 12;
+
+/// Derives the key of one file from the node's signing key. Ed25519 signatures are deterministic,
+/// so the owner can always re-derive it, while handing it out only opens this single file.
+pub fn file_key(node: &Node, request_id: &str) -> Result<[u8; 32], Box<dyn Error>> {
+    let signature = node
+        .private_key
+        .sign(format!("pastechain-file-key:{request_id}").as_bytes())?;
+    Ok(Sha256::digest(&signature).into())
+}
+
+/// Encrypts with ChaCha20-Poly1305, the random nonce is prepended to the ciphertext
+pub fn encrypt(key: &[u8; 32], plaintext: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(key));
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .encrypt(&nonce, plaintext)
+        .map_err(|_| "failed to encrypt file")?;
+
+    Ok([nonce.as_slice(), &ciphertext].concat())
+}
+
+pub fn decrypt(key: &[u8; 32], data: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    if data.len() < NONCE_LEN {
+        return Err("encrypted file is too short".into());
+    }
+    let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(key));
+
+    Ok(cipher
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| "wrong key or tampered file")?)
+}
+
+pub fn parse_key(capability: &str) -> Result<[u8; 32], Box<dyn Error>> {
+    let key: [u8; 32] = hex::decode(capability)?
+        .try_into()
+        .map_err(|_| "capability key must be 32 bytes")?;
+    Ok(key)
+}
@@ -0,0 +1,88 @@
+use reed_solomon_erasure::galois_8::ReedSolomon;
+use sha2::{Digest, Sha256};
+use std::error::Error;
+
+use crate::transaction::ShardInfo;
+
+pub fn shard_request_id(file_id: &str, index: usize) -> String {
+    format!("{file_id}.{index}")
+}
+
+/// Splits `data` into `data_shards` equally sized pieces (zero padded) followed by `parity_shards`
+/// Reed-Solomon parity pieces, so that any `data_shards` of them rebuild the file
+pub fn encode(
+    data: &[u8],
+    data_shards: usize,
+    parity_shards: usize,
+) -> Result<Vec<Vec<u8>>, Box<dyn Error>> {
+    let rs = ReedSolomon::new(data_shards, parity_shards)?;
+    let shard_len = data.len().div_ceil(data_shards).max(1);
+
+    let mut shards: Vec<Vec<u8>> = (0..data_shards + parity_shards)
+        .map(|i| {
+            let start = (i * shard_len).min(data.len());
+            let end = ((i + 1) * shard_len).min(data.len());
+            let mut shard = if i < data_shards {
+                data[start..end].to_vec()
+            } else {
+                vec![]
+            };
+            shard.resize(shard_len, 0);
+            shard
+        })
+        .collect();
+    rs.encode(&mut shards)?;
+
+    Ok(shards)
+}
+
+/// Rebuilds the original file from the shards received so far, `None` marking missing ones
+pub fn reconstruct(
+    shards: &mut [Option<Vec<u8>>],
+    info: &ShardInfo,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let rs = ReedSolomon::new(info.data_shards, info.parity_shards)?;
+    rs.reconstruct_data(shards)?;
+
+    let mut data: Vec<u8> = shards[..info.data_shards]
+        .iter()
+        .flat_map(|shard| shard.iter().flatten().copied())
+        .collect();
+    data.truncate(info.file_size);
+
+    if format!("{:x}", Sha256::digest(&data)) != info.file_hash {
+        return Err("reconstructed file does not match its hash".into());
+    }
+
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rebuilds_after_losing_parity_shards() {
+        let data: Vec<u8> = (0..1000u32).map(|i| (i * 7) as u8).collect();
+        let info = ShardInfo {
+            file_id: "file".to_string(),
+            index: 0,
+            data_shards: 4,
+            parity_shards: 2,
+            file_size: data.len(),
+            file_hash: format!("{:x}", Sha256::digest(&data)),
+        };
+        let mut shards: Vec<Option<Vec<u8>>> =
+            encode(&data, 4, 2).unwrap().into_iter().map(Some).collect();
+        // lose a data shard and a parity shard
+        shards[1] = None;
+        shards[4] = None;
+
+        assert_eq!(reconstruct(&mut shards, &info).unwrap(), data);
+
+        shards[0] = None;
+        shards[2] = None;
+        shards[3] = None;
+        assert!(reconstruct(&mut shards, &info).is_err());
+    }
+}
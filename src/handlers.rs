@@ -0,0 +1,202 @@
+use libp2p::{PeerId, Swarm};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::error::Error;
+
+use crate::blob_store::BlobStore;
+use crate::blockchain::Blockchain;
+use crate::chunking;
+use crate::data::Data;
+use crate::market::Negotiation;
+use crate::mempool::{MemPoolRequest, Mempool};
+use crate::network::{Gossip, MyBehaviour};
+use crate::node::Node;
+use crate::post;
+use crate::replication::ReplicaMonitor;
+use crate::reputation::{Offence, Reputation};
+use crate::transaction::{
+    Ask, Bid, CapacityTx, DealTx, LifecycleTx, ProofOfStorageTx, RetrievalReceipt, TransferTx,
+};
+
+// one handler per gossiped message type, each called only after the message passed validation
+
+pub fn blockchain(mut received: Blockchain, blockchain: &mut Blockchain) {
+    // replayed and verified during validation
+    blockchain.update(&mut received);
+}
+
+pub fn request(request: MemPoolRequest, mempool: &mut Mempool, blockchain: &Blockchain) {
+    mempool.insert(request, blockchain).ok();
+}
+
+pub fn receipt(
+    receipt: RetrievalReceipt,
+    receipts: &mut VecDeque<RetrievalReceipt>,
+) -> Result<(), Box<dyn Error>> {
+    if !receipt.verify()? {
+        return Err("invalid receipt".into());
+    }
+    receipts.push_back(receipt);
+    Ok(())
+}
+
+pub fn lifecycle(
+    ltx: LifecycleTx,
+    lifecycle: &mut VecDeque<LifecycleTx>,
+) -> Result<(), Box<dyn Error>> {
+    if !ltx.verify()? {
+        return Err("invalid lifecycle tx".into());
+    }
+    lifecycle.push_back(ltx);
+    Ok(())
+}
+
+pub fn transfer(ttx: TransferTx, transfers: &mut VecDeque<TransferTx>) {
+    if !transfers
+        .iter()
+        .any(|other| other.signature == ttx.signature)
+    {
+        transfers.push_back(ttx);
+    }
+}
+
+pub fn capacity(
+    ctx: CapacityTx,
+    declarations: &mut VecDeque<CapacityTx>,
+) -> Result<(), Box<dyn Error>> {
+    if !ctx.verify()? {
+        return Err("invalid capacity declaration".into());
+    }
+    if !declarations
+        .iter()
+        .any(|other| other.signature == ctx.signature)
+    {
+        declarations.push_back(ctx);
+    }
+    Ok(())
+}
+
+pub fn ask(ask: Ask, asks: &mut HashMap<String, Ask>) -> Result<(), Box<dyn Error>> {
+    if !ask.verify()? {
+        return Err("invalid ask".into());
+    }
+    asks.insert(ask.miner_id.clone(), ask);
+    Ok(())
+}
+
+/// Answers each bid we can serve once, at our own price
+#[allow(clippy::too_many_arguments)]
+pub fn bid(
+    bid: Bid,
+    node: &Node,
+    ask: &Ask,
+    blockchain: &Blockchain,
+    blobs: &dyn BlobStore,
+    offered: &mut HashSet<String>,
+    swarm: &mut Swarm<MyBehaviour>,
+    gossip: &Gossip,
+) -> Result<(), Box<dyn Error>> {
+    if bid.verify()?
+        && bid.client_id != node.id
+        && bid.file_size <= blockchain.free_space(&node.id)
+        && bid.file_size <= blobs.available()
+        && !offered.contains(&bid.request_id)
+    {
+        let offer = DealTx::offer(node, &bid, ask)?;
+        Data::broadcast(node, &offer, swarm, gossip)?;
+        // only marked once it went out, so a failed offer is made again on the next bid
+        offered.insert(bid.request_id);
+    }
+    Ok(())
+}
+
+/// Countersigns deals for our negotiations or our ask, and queues complete ones to be recorded
+pub fn deal(
+    received: DealTx,
+    node: &Node,
+    ask: &Ask,
+    negotiations: &mut HashMap<String, Negotiation>,
+    deals: &mut VecDeque<DealTx>,
+    swarm: &mut Swarm<MyBehaviour>,
+    gossip: &Gossip,
+) -> Result<(), Box<dyn Error>> {
+    if !received.verify()? {
+        return Err("invalid deal".into());
+    }
+    let dtx = if let Some(negotiation) = negotiations.get_mut(&received.request_id) {
+        negotiation.accept(node, received)?
+    } else if received.miner_id == node.id && received.miner_signature.is_empty() {
+        // a client took our ask, countersign if it still is our ask
+        let mut dtx = received;
+        if !ask.fits(dtx.file_size, dtx.duration) || dtx.price != ask.price {
+            return Err("deal does not match our ask".into());
+        }
+        dtx.sign(node)?;
+        Some(dtx)
+    } else {
+        Some(received).filter(|dtx| dtx.is_complete())
+    };
+    if let Some(dtx) = dtx
+        && !deals
+            .iter()
+            .any(|other| other.request_id == dtx.request_id && other.miner_id == dtx.miner_id)
+    {
+        Data::broadcast(node, &dtx, swarm, gossip).ok();
+        deals.push_back(dtx);
+    }
+    Ok(())
+}
+
+/// Checks a proof for a file we hold too and rewards the prover, `source` answers for a bad one.
+/// Proofs for other files are only recorded.
+#[allow(clippy::too_many_arguments)]
+pub fn proof(
+    proof: ProofOfStorageTx,
+    source: Option<PeerId>,
+    node: &Node,
+    blockchain: &mut Blockchain,
+    blobs: &dyn BlobStore,
+    replica_monitor: &mut ReplicaMonitor,
+    reputation: &mut Reputation,
+    swarm: &mut Swarm<MyBehaviour>,
+) -> Result<(), Box<dyn Error>> {
+    let Some(list) = blockchain.stored.get(&proof.request_id) else {
+        return Err("invalid received_proof".into());
+    };
+    if !list.contains(&node.id) {
+        // we cannot check it without the file, but a holder that keeps proving is not dropped by
+        // the repairs we mine
+        replica_monitor.record_proof(&proof.request_id, &proof.node_id);
+        return Ok(());
+    }
+
+    println!("{:#?}", proof);
+    // Read the file content
+    let file_content = match chunking::read_file(blobs, blockchain, &proof.request_id) {
+        Ok(content) => content,
+        Err(e) => {
+            println!(
+                "[!!] Failed to read file for request_id {}: {e}",
+                proof.request_id
+            );
+            return Err("failed to read file".into());
+        }
+    };
+    if !post::validate(&file_content, proof.start, proof.end, &proof.proof_hash) {
+        if let Some(prover) = source {
+            reputation.penalize(swarm, prover, Offence::BadProof);
+        }
+        return Err("invalid proof".into());
+    }
+    println!(
+        "[+] Node {} successfully proved the storage for file {}",
+        proof.node_id, proof.request_id
+    );
+    replica_monitor.record_proof(&proof.request_id, &proof.node_id);
+    blockchain.reward(&proof.node_id, 0.01);
+    println!(
+        "[+] Balance of Node {} is {}",
+        proof.node_id,
+        blockchain.balance.get(&proof.node_id).unwrap()
+    );
+    Ok(())
+}
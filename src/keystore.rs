@@ -0,0 +1,115 @@
+use argon2::Argon2;
+use libp2p::identity::Keypair;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+use crate::encryption;
+
+const SALT_LEN: usize = 16;
+
+/// Secret as kept on disk, sealed with a key derived from the passphrase when one is set
+#[derive(Serialize, Deserialize)]
+struct KeyFile {
+    /// Hex of the secret, or of its ciphertext when `salt` is set
+    key: String,
+    #[serde(default)]
+    salt: Option<String>,
+}
+
+fn derive_key(passphrase: &str, salt: &[u8]) -> Result<[u8; 32], Box<dyn Error>> {
+    let mut key = [0u8; 32];
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| format!("cannot derive keystore key: {e}"))?;
+    Ok(key)
+}
+
+/// Loads the node key from `path`, creating it on first start, so the node keeps its id, and with
+/// it its balances and storage obligations, across restarts
+pub fn load_or_create(path: &Path, passphrase: Option<&str>) -> Result<Keypair, Box<dyn Error>> {
+    if let Some(key) = read_sealed(path, passphrase)? {
+        return Ok(Keypair::from_protobuf_encoding(&key)?);
+    }
+    let keypair = Keypair::generate_ed25519();
+    write_sealed(path, &keypair.to_protobuf_encoding()?, passphrase)?;
+    println!("[+] Created node key in {}", path.display());
+    Ok(keypair)
+}
+
+/// Reads a secret written by `write_sealed`, `None` if there is no file yet
+pub fn read_sealed(
+    path: &Path,
+    passphrase: Option<&str>,
+) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let file: KeyFile = serde_json::from_slice(&bytes)?;
+    let key = hex::decode(&file.key)?;
+    let key = match (&file.salt, passphrase) {
+        (Some(salt), Some(passphrase)) => {
+            let salt = hex::decode(salt)?;
+            encryption::decrypt(&derive_key(passphrase, &salt)?, &key)
+                .map_err(|_| format!("wrong passphrase or corrupt {}", path.display()))?
+        }
+        (Some(_), None) => {
+            return Err(
+                format!("{} is encrypted, set PASTECHAIN_PASSPHRASE", path.display()).into(),
+            );
+        }
+        // a plaintext file is sealed as soon as a passphrase is set, it never stays readable
+        (None, Some(passphrase)) => {
+            write_sealed(path, &key, Some(passphrase))?;
+            println!("[+] Encrypted {} with the passphrase", path.display());
+            key
+        }
+        (None, None) => key,
+    };
+    Ok(Some(key))
+}
+
+/// Writes a secret readable only by the owner, encrypted when a passphrase is set
+pub fn write_sealed(
+    path: &Path,
+    secret: &[u8],
+    passphrase: Option<&str>,
+) -> Result<(), Box<dyn Error>> {
+    let file = match passphrase {
+        Some(passphrase) => {
+            let salt: [u8; SALT_LEN] = rand::random();
+            KeyFile {
+                key: hex::encode(encryption::encrypt(
+                    &derive_key(passphrase, &salt)?,
+                    secret,
+                )?),
+                salt: Some(hex::encode(salt)),
+            }
+        }
+        None => KeyFile {
+            key: hex::encode(secret),
+            salt: None,
+        },
+    };
+
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    // created owner-only, a leftover from an earlier crash may have other permissions
+    let tmp = path.with_extension("tmp");
+    fs::remove_file(&tmp).ok();
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(&tmp)?.write_all(&serde_json::to_vec(&file)?)?;
+    fs::rename(tmp, path)?;
+    Ok(())
+}
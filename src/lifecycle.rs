@@ -0,0 +1,90 @@
+use std::error::Error;
+
+use crate::blockchain::{Blockchain, STORAGE_FEE_PER_EPOCH};
+use crate::node::{Node, verify_signature};
+use crate::transaction::{LifecycleAction, LifecycleTx};
+
+impl LifecycleTx {
+    fn signing_bytes(&self) -> Vec<u8> {
+        serde_json::to_vec(&(&self.request_id, &self.owner, &self.action)).unwrap_or_default()
+    }
+
+    fn new(node: &Node, request_id: &str, action: LifecycleAction) -> Result<Self, Box<dyn Error>> {
+        let mut ltx = LifecycleTx {
+            request_id: request_id.to_string(),
+            owner: node.id.clone(),
+            action,
+            signature: vec![],
+            public_key: node.public_key.clone(),
+        };
+        ltx.signature = node.private_key.sign(&ltx.signing_bytes())?;
+
+        Ok(ltx)
+    }
+
+    /// Extends the deal of a file by `epochs`, paying every replica for the extra time
+    pub fn new_renewal(
+        node: &Node,
+        blockchain: &Blockchain,
+        request_id: &str,
+        epochs: u64,
+    ) -> Result<Self, Box<dyn Error>> {
+        let end_epoch = blockchain
+            .deals
+            .get(request_id)
+            .ok_or("file has no deal to renew")?
+            + epochs;
+        let escrow = epochs as f64 * blockchain.replicas(request_id) as f64 * STORAGE_FEE_PER_EPOCH;
+        Self::new(
+            node,
+            request_id,
+            LifecycleAction::Renew { end_epoch, escrow },
+        )
+    }
+
+    pub fn new_deletion(node: &Node, request_id: &str) -> Result<Self, Box<dyn Error>> {
+        Self::new(node, request_id, LifecycleAction::Delete)
+    }
+
+    pub fn verify(&self) -> Result<bool, Box<dyn Error>> {
+        verify_signature(
+            &self.owner,
+            &self.public_key,
+            &self.signing_bytes(),
+            &self.signature,
+        )
+    }
+
+    /// Checks that the owner signed the change and that it still applies on top of `blockchain`
+    pub fn check(&self, blockchain: &Blockchain) -> Result<(), Box<dyn Error>> {
+        if !self.verify()? {
+            return Err("invalid lifecycle signature".into());
+        }
+        let stx = blockchain
+            .find_storage_tx(&self.request_id)
+            .ok_or("lifecycle tx for unknown file")?;
+        if stx.owner != self.owner {
+            return Err("only the owner may change a storage deal".into());
+        }
+        if blockchain.has_ended(&self.request_id) {
+            return Err("storage deal has ended".into());
+        }
+
+        if let LifecycleAction::Renew { end_epoch, escrow } = &self.action {
+            let current = blockchain
+                .deals
+                .get(&self.request_id)
+                .ok_or("file has no deal to renew")?;
+            if end_epoch <= current {
+                return Err("renewal does not extend the deal".into());
+            }
+            let expected = (end_epoch - current) as f64
+                * blockchain.replicas(&self.request_id) as f64
+                * STORAGE_FEE_PER_EPOCH;
+            if *escrow != expected {
+                return Err("escrow does not cover the extension".into());
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,28 +1,66 @@
+mod acl;
+mod blob_store;
 mod block;
 mod blockchain;
+mod capacity;
+mod chunking;
+mod codec;
+mod config;
 mod data;
+mod download;
+mod encryption;
+mod erasure;
+mod handlers;
+mod keystore;
+mod lifecycle;
+mod market;
 mod mempool;
+mod merkle;
+mod message;
 mod network;
 mod node;
+mod payment;
 mod post;
+mod providers;
 mod randomized_election;
+mod replication;
+mod reputation;
+mod store;
 mod transaction;
+mod transfer;
 mod utils;
+mod wallet;
 
-use blockchain::Blockchain;
+use blob_store::BlobStore;
+use blockchain::{
+    Blockchain, DEFAULT_DEAL_EPOCHS, DEFAULT_REPLICATION_FACTOR, RETRIEVAL_FEE_PER_CHUNK,
+};
+use config::Config;
 use data::Data;
-use libp2p::{gossipsub, mdns, swarm::SwarmEvent};
-use mempool::MemPoolRequest;
+use download::{DownloadManager, FetchManager, Fetched};
+use libp2p::{
+    PeerId, core::ConnectedPoint, gossipsub, kad, mdns, multiaddr::Protocol, request_response,
+    swarm::SwarmEvent,
+};
+use market::Negotiation;
+use mempool::{MemPoolRequest, Mempool};
+use message::Message;
 use network::MyBehaviourEvent;
 use node::Node;
+use providers::Providers;
+use replication::ReplicaMonitor;
+use reputation::{Offence, Reputation};
 use sha2::digest;
+use store::Store;
 use transaction::*;
+use transfer::ChunkResponse;
+use wallet::Wallet;
 
 use futures::stream::StreamExt;
 use std::{
-    collections::{HashSet, VecDeque},
+    collections::{HashMap, HashSet, VecDeque},
     error::Error,
-    fs::{self, File},
+    fs::File,
     io::Write,
     panic,
 };
@@ -32,29 +70,96 @@ use tokio::{io, io::AsyncBufReadExt, select, time, time::Duration}; // Import th
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    let (mut swarm, topic) = network::setup_p2p_network()?;
+    let config = Config::from_env();
+    // one key is both the libp2p identity and the node id balances and deals are tied to
+    let keypair = keystore::load_or_create(&config.key_file(), config.passphrase.as_deref())?;
+    let (mut swarm, mut gossip) = network::setup_p2p_network(&config, keypair.clone())?;
 
-    let node = Node::new();
-    let mut blockchain = Blockchain::new_with_genesis_block();
+    let node = Node::new(keypair);
+    let mut wallet = Wallet::load(config.wallet_file(), config.passphrase.clone())?;
+    let mut blobs = blob_store::open(&config)?;
+    let (mut store, mut blockchain, pending) = Store::open(config.store_dir())?;
     let mut stdin = io::BufReader::new(io::stdin()).lines();
     let mut broadcast_timer = time::interval(Duration::from_secs(2));
     let mut mine_timer = time::interval(Duration::from_secs(2));
     let mut validate_timer = time::interval(Duration::from_secs(10));
-    let mut serving_q: VecDeque<String> = VecDeque::new();
-    let mut mempool: VecDeque<MemPoolRequest> = VecDeque::new();
+    let mut discovery_timer = time::interval(Duration::from_secs(30));
+    let mut mempool = Mempool::load(pending.mempool, config.mempool_bytes, &blockchain);
+    let mut replica_monitor = ReplicaMonitor::new();
+    let mut reputation = Reputation::default();
+    let mut providers = Providers::default();
+    let mut uploads: HashMap<String, Vec<String>> = pending.uploads;
+    let mut fetches = FetchManager::default();
+    let mut downloads = DownloadManager::load(config.download_dir());
+    let mut receipts: VecDeque<RetrievalReceipt> = VecDeque::new();
+    let mut lifecycle: VecDeque<LifecycleTx> = VecDeque::new();
+    let mut declarations: VecDeque<CapacityTx> = VecDeque::new();
+    let mut transfers: VecDeque<TransferTx> = VecDeque::new();
+    let ask = Ask::new(&node, config.price, 0, config.capacity, DEFAULT_DEAL_EPOCHS)?;
+    let mut asks: HashMap<String, Ask> = HashMap::new();
+    let mut offered: HashSet<String> = HashSet::new();
+    let mut negotiations: HashMap<String, Negotiation> = HashMap::new();
+    let mut deals: VecDeque<DealTx> = VecDeque::new();
+    let mut capabilities: HashMap<String, [u8; 32]> = HashMap::new();
+    let mut pending_shards: HashMap<String, Vec<Option<Vec<u8>>>> = HashMap::new();
     let mut set_of_nodes: HashSet<String> = HashSet::new();
     set_of_nodes.insert(node.id.to_string());
 
+    // miners are only assigned files once their capacity and price are on-chain
+    let declared = blockchain
+        .capacities
+        .get(&node.id)
+        .is_some_and(|ctx| ctx.capacity == config.capacity && ctx.price == config.price);
+    if !declared {
+        declarations.push_back(CapacityTx::new(
+            &node,
+            &blockchain,
+            config.capacity,
+            config.price,
+        )?);
+    }
+
     loop {
         select! {
             _ = validate_timer.tick() => {
+                reputation.decay(&mut swarm);
+                let held = blockchain
+                    .stored
+                    .iter()
+                    .filter(|(_, holders)| holders.contains(&node.id))
+                    .map(|(request_id, _)| request_id.clone())
+                    .collect();
+                providers.refresh(&mut swarm.behaviour_mut().kad, held);
+                mempool.prune(&blockchain);
+
+                // drop the data of deals that ended, keeping chunks shared with files we still hold or upload
+                let ended: Vec<String> = blockchain
+                    .ended_deals(&node.id)
+                    .iter()
+                    .flat_map(|stx| stx.chunks.clone())
+                    .collect();
+                if !ended.is_empty() {
+                    let live: HashSet<String> = blockchain
+                        .stored
+                        .iter()
+                        .filter(|(_, holders)| holders.contains(&node.id))
+                        .filter_map(|(request_id, _)| blockchain.find_storage_tx(request_id))
+                        .flat_map(|stx| stx.chunks.clone())
+                        .chain(uploads.values().flatten().cloned())
+                        .collect();
+                    let removed = chunking::remove_chunks(blobs.as_mut(), &ended, &live);
+                    if removed > 0 {
+                        println!("[#] Dropped {removed} chunks of ended storage deals");
+                    }
+                }
+
                 for (request_id, list) in &blockchain.stored {
                     if !list.contains(&node.id) {
                         continue;
                     }
 
                     // Read the file content
-                    let file_content = match fs::read(request_id) {
+                    let file_content = match chunking::read_file(blobs.as_ref(), &blockchain, request_id) {
                         Ok(content) => content,
                         Err(e) => {
                             println!("[!!] Failed to read file for request_id {request_id}: {e}");
@@ -83,55 +188,495 @@ async fn main() -> Result<(), Box<dyn Error>> {
                     };
 
                     // Broadcast the proof
-                    Data::broadcast(&node, &proof, &mut swarm, &topic)
+                    Data::broadcast(&node, &proof, &mut swarm, &gossip)
                         .inspect_err(|e| println!("[!!] Failed to broadcast proof: {e}"))
                         .ok();
                 }
+
+                // re-offer files that dropped below k healthy replicas
+                for (request_id, dropped) in replica_monitor.next_epoch(&blockchain, &node.id) {
+                    match MemPoolRequest::new_repair(blobs.as_ref(), &node, &blockchain, &request_id, dropped) {
+                        Ok(request) => {
+                            println!("[#] Requesting repair of {request_id}");
+                            Data::broadcast(&node, &request, &mut swarm, &gossip).ok();
+                            mempool.insert(request, &blockchain).ok();
+                        }
+                        Err(e) => println!("[!!] Cannot request repair of {request_id}: {e}"),
+                    }
+                }
+            }
+
+            _ = discovery_timer.tick() => {
+                // random walk, every lookup fills our routing table with peers near a random key
+                swarm.behaviour_mut().kad.get_closest_peers(PeerId::random());
             }
 
             _ = broadcast_timer.tick() => {
-                Data::broadcast(&node, &blockchain, &mut swarm, &topic).inspect_err(|e| {}).ok();
-                if let Some(request) = mempool.front() {
-                    Data::broadcast(&node, &request, &mut swarm, &topic).inspect_err(|e| {}).ok();
-                }
-                if let Some(request_id) = serving_q.front() {
-                    let stx = ServeFileTx{
-                        request_id: request_id.to_string(),
-                        file_content: fs::read(request_id)?
-                    };
-                    Data::broadcast(&node, &stx, &mut swarm, &topic).inspect_err(|e| {}).ok();
+                Data::broadcast(&node, &blockchain, &mut swarm, &gossip).ok();
+                // the best paying requests, the rest wait for room in blocks anyway
+                for request in mempool.by_priority().into_iter().take(4) {
+                    Data::broadcast(&node, request, &mut swarm, &gossip).ok();
+                }
+                if let Some(ctx) = declarations.front() {
+                    Data::broadcast(&node, ctx, &mut swarm, &gossip).ok();
+                }
+                if let Some(ttx) = transfers.front() {
+                    Data::broadcast(&node, ttx, &mut swarm, &gossip).ok();
                 }
+                Data::broadcast(&node, &ask, &mut swarm, &gossip).ok();
+                for negotiation in negotiations.values() {
+                    if let Some(bid) = &negotiation.bid
+                        && negotiation.accepted.len() < bid.replicas
+                    {
+                        Data::broadcast(&node, bid, &mut swarm, &gossip).ok();
+                    }
+                }
+                downloads.poll(&mut swarm.behaviour_mut().transfer);
             }
 
             _ = mine_timer.tick() => {
-                if let Some(request) = mempool.front() {
-                    request.mine(&node, &mut blockchain, set_of_nodes.len()).inspect_err(|e| {}).ok();
-                    mempool.pop_front();
+                // receipts that are not settled yet are retried until some elected node mines them
+                if let Some(receipt) = receipts.pop_front()
+                    && blockchain.propose_block(&node, receipt.settlement(), set_of_nodes.len()).is_err()
+                    && !blockchain.is_settled(&receipt.nonce)
+                    && blockchain.find_storage_tx(&receipt.request_id).is_some()
+                {
+                    receipts.push_back(receipt);
+                }
+                if let Some(ltx) = lifecycle.pop_front()
+                    && blockchain.propose_block(&node, ltx.clone(), set_of_nodes.len()).is_err()
+                    && ltx.check(&blockchain).is_ok()
+                {
+                    lifecycle.push_back(ltx);
+                }
+                if let Some(ctx) = declarations.pop_front()
+                    && blockchain.propose_block(&node, ctx.clone(), set_of_nodes.len()).is_err()
+                    && ctx.check(&blockchain).is_ok()
+                {
+                    declarations.push_back(ctx);
+                }
+                if let Some(dtx) = deals.pop_front()
+                    && blockchain.propose_block(&node, dtx.clone(), set_of_nodes.len()).is_err()
+                    && dtx.check(&blockchain).is_ok()
+                {
+                    deals.push_back(dtx);
+                }
+                if let Some(ttx) = transfers.pop_front()
+                    && blockchain.propose_block(&node, ttx.clone(), set_of_nodes.len()).is_err()
+                    && (ttx.check(&blockchain).is_ok() || ttx.nonce > blockchain.next_nonce(&ttx.from))
+                {
+                    transfers.push_back(ttx);
+                }
+
+                // files whose deals are all on-chain are released to their miners
+                let settled: Vec<String> = negotiations
+                    .iter()
+                    .filter(|(_, negotiation)| negotiation.is_settled(&blockchain))
+                    .map(|(request_id, _)| request_id.clone())
+                    .collect();
+                for request_id in settled {
+                    if let Some(negotiation) = negotiations.remove(&request_id) {
+                        println!("[+] Deals for {request_id} are on-chain, sending it to the miners");
+                        // the deals already pay the miners from escrow, so no fee is offered on top
+                        let request = negotiation.request;
+                        Data::broadcast(&node, &request, &mut swarm, &gossip).ok();
+                        uploads.insert(request.request_id.clone(), request.chunks.clone());
+                        mempool.insert(request, &blockchain).inspect_err(|e| println!("[!!] Request not queued: {e}")).ok();
+                    }
+                }
+
+                // only elected miners pull the file, straight from the node that offered it
+                // and only if it fits within the capacity this node offers
+                // the best paying request this node can serve goes first
+                let next = mempool
+                    .by_priority()
+                    .into_iter()
+                    .find(|request| {
+                        // a repair is only served if we saw its dropped holders miss their challenges too
+                        request.repair.as_ref().is_none_or(|repair| {
+                            repair.dropped.iter().all(|holder| replica_monitor.has_missed(&repair.request_id, holder))
+                        })
+                            && request.file_size as u64 <= blobs.available()
+                            && request.propose(&node.id, &blockchain, set_of_nodes.len()).is_ok()
+                    })
+                    .map(|request| request.request_id.clone());
+                if let Some(request) = next.and_then(|request_id| mempool.remove(&request_id)) {
+                    let missing = chunking::missing_chunks(blobs.as_ref(), &request.chunks);
+                    match request.node_id.parse::<PeerId>() {
+                        Ok(source) if !missing.is_empty() => {
+                            fetches.start(source, request, missing);
+                            fetches.poll(&mut swarm.behaviour_mut().transfer);
+                        }
+                        _ => {
+                            request.mine(&node, &mut blockchain, set_of_nodes.len(), blobs.as_ref()).ok();
+                        }
+                    }
                 }
             }
 
             Ok(Some(line)) = stdin.next_line() => {
-                if line[0..3] == *"GET" {
-                    let query = QueryTx {
-                        request_id: line[4..].to_string(),
+                // `PUT <k> <path>` asks for k replicas, a bare path gets the default
+                let (replicas, path) = line
+                    .strip_prefix("PUT ")
+                    .and_then(|rest| rest.split_once(' '))
+                    .and_then(|(k, path)| Some((k.parse().ok()?, path)))
+                    .unwrap_or((DEFAULT_REPLICATION_FACTOR, line.as_str()));
+
+                if let Some(args) = line.strip_prefix("GET ") {
+                    // `GET <id> <key>` decrypts the file with the capability key handed out by its owner
+                    let request_id = match args.split_once(' ') {
+                        Some((request_id, capability)) => {
+                            match encryption::parse_key(capability) {
+                                Ok(key) => {
+                                    capabilities.insert(request_id.to_string(), key);
+                                }
+                                Err(e) => println!("[!!] Invalid capability key: {e}"),
+                            }
+                            request_id
+                        }
+                        None => args,
+                    };
+                    let shards = blockchain.shards_of(request_id);
+                    if let Some(info) = shards.first().and_then(|stx| stx.shard.as_ref()) {
+                        // erasure-coded files are rebuilt once enough shards come back
+                        pending_shards.insert(request_id.to_string(), vec![None; info.data_shards + info.parity_shards]);
+                    }
+                    let request_ids: Vec<String> = if shards.is_empty() {
+                        vec![request_id.to_string()]
+                    } else {
+                        shards.iter().map(|stx| stx.request_id.clone()).collect()
+                    };
+                    // chunks are pulled straight from the holders recorded on-chain
+                    for request_id in request_ids {
+                        let started = blockchain
+                            .find_storage_tx(&request_id)
+                            .ok_or_else(|| "unknown request id".into())
+                            .and_then(|stx| match &stx.acl {
+                                Some(acl) if !acl.allows(&node.id) => Err("not on the file's access list".into()),
+                                _ => downloads.start(stx, blockchain.holders(&request_id)),
+                            });
+                        match started {
+                            // holders the chain does not know about yet may be found in the DHT
+                            Ok(()) => providers.find(&mut swarm.behaviour_mut().kad, &request_id),
+                            Err(e) => println!("[!!] Cannot retrieve {request_id}: {e}"),
+                        }
+                    }
+                    downloads.poll(&mut swarm.behaviour_mut().transfer);
+                } else if let Some(args) = line.strip_prefix("SHARD ") {
+                    // `SHARD <data> <parity> <path>` spreads the file over data + parity miners
+                    let mut args = args.splitn(3, ' ');
+                    let data_shards = args.next().and_then(|n| n.parse().ok());
+                    let parity_shards = args.next().and_then(|n| n.parse().ok());
+                    let requests = match (data_shards, parity_shards, args.next()) {
+                        (Some(data_shards), Some(parity_shards), Some(path)) => {
+                            MemPoolRequest::new_sharded(blobs.as_mut(), node.id.to_string(), path, data_shards, parity_shards)
+                                .inspect_err(|e| println!("[!!] Failed to shard {path}: {e}"))
+                                .unwrap_or_default()
+                        }
+                        _ => vec![],
+                    };
+                    if let Some(info) = requests.first().and_then(|request| request.shard.as_ref()) {
+                        println!("Request id: {}", info.file_id);
+                    }
+                    for mut request in requests {
+                        offer_fee(&node, &blockchain, &mut request, config.fee);
+                        Data::broadcast(&node, &request, &mut swarm, &gossip).ok();
+                        uploads.insert(request.request_id.clone(), request.chunks.clone());
+                        mempool.insert(request, &blockchain).inspect_err(|e| println!("[!!] Request not queued: {e}")).ok();
+                    }
+                } else if let Some(args) = line.strip_prefix("RENEW ") {
+                    // `RENEW <id> <epochs>` extends the storage deal, paying the replicas for the extra epochs
+                    let renewal = args
+                        .split_once(' ')
+                        .and_then(|(request_id, epochs)| Some((request_id, epochs.parse().ok()?)))
+                        .ok_or_else(|| "usage: RENEW <id> <epochs>".into())
+                        .and_then(|(request_id, epochs)| LifecycleTx::new_renewal(&node, &blockchain, request_id, epochs))
+                        .and_then(|ltx| {
+                            // the chain does not check balances, so we only lock what we know we have
+                            if let LifecycleAction::Renew { escrow, .. } = &ltx.action
+                                && *escrow > blockchain.balance.get(&node.id).copied().unwrap_or(0.0)
+                            {
+                                return Err("insufficient balance".into());
+                            }
+                            Ok(ltx)
+                        });
+                    match renewal {
+                        Ok(ltx) => {
+                            Data::broadcast(&node, &ltx, &mut swarm, &gossip).ok();
+                            lifecycle.push_back(ltx);
+                        }
+                        Err(e) => println!("[!!] Failed to renew {args}: {e}"),
+                    }
+                } else if let Some(request_id) = line.strip_prefix("DELETE ") {
+                    // `DELETE <id>` ends the storage deal, the holders drop the file
+                    match LifecycleTx::new_deletion(&node, request_id) {
+                        Ok(ltx) => {
+                            Data::broadcast(&node, &ltx, &mut swarm, &gossip).ok();
+                            uploads.remove(request_id);
+                            lifecycle.push_back(ltx);
+                        }
+                        Err(e) => println!("[!!] Failed to delete {request_id}: {e}"),
+                    }
+                } else if let Some(args) = line.strip_prefix("BID ") {
+                    // `BID <max price> <k> <path>` asks k miners to store the file for at most the given price
+                    let mut args = args.splitn(3, ' ');
+                    let max_price = args.next().and_then(|price| price.parse().ok());
+                    let replicas = args.next().and_then(|k| k.parse().ok());
+                    let negotiation: Result<Negotiation, Box<dyn Error>> = match (max_price, replicas, args.next()) {
+                        (Some(max_price), Some(replicas), Some(path)) => {
+                            MemPoolRequest::new(blobs.as_mut(), node.id.to_string(), path, replicas).and_then(|request| {
+                                let bid = Bid::new(&node, &request, max_price)?;
+                                // the chain does not check balances, so we only bid what we know we have
+                                if bid.max_amount() > blockchain.balance.get(&node.id).copied().unwrap_or(0.0) {
+                                    return Err("insufficient balance".into());
+                                }
+                                Ok(Negotiation {
+                                    bid: Some(bid),
+                                    request,
+                                    max_price,
+                                    accepted: vec![],
+                                })
+                            })
+                        }
+                        _ => Err("usage: BID <max price> <k> <path>".into()),
                     };
-                    Data::broadcast(&node, &query, &mut swarm, &topic).inspect_err(|e| {}).ok();
-                } else if let Some(request) = {
-                    MemPoolRequest::new(node.id.to_string(), &line)
-                        .inspect_err(|e| {})
+                    match negotiation {
+                        Ok(negotiation) => {
+                            println!("Request id: {}", negotiation.request.request_id);
+                            negotiations.insert(negotiation.request.request_id.clone(), negotiation);
+                        }
+                        Err(e) => println!("[!!] Failed to bid: {e}"),
+                    }
+                } else if let Some(name) = line.strip_prefix("ACCOUNT ") {
+                    match wallet.create(name.trim()) {
+                        Ok(account) => println!("[+] Created account {} with address {}", account.name, account.address()),
+                        Err(e) => println!("[!!] Cannot create account: {e}"),
+                    }
+                } else if line == "ACCOUNTS" {
+                    let balance = |id: &str| blockchain.balance.get(id).copied().unwrap_or(0.0);
+                    println!("node {} has {}", node.id, balance(&node.id));
+                    for account in &wallet.accounts {
+                        let address = account.address();
+                        println!("{} {} has {}", account.name, address, balance(&address));
+                    }
+                } else if let Some(args) = line.strip_prefix("SEND ") {
+                    // `SEND <account or node> <account, address or node id> <amount>`
+                    let mut args = args.split_whitespace();
+                    let (from, to, amount) = (args.next(), args.next(), args.next().and_then(|amount| amount.parse::<f64>().ok()));
+                    let to = to.map(|to| wallet.account(to).map_or(to.to_string(), |account| account.address()));
+                    let transfer = match (from, to, amount) {
+                        (Some("node"), Some(to), Some(amount)) => {
+                            let nonce = blockchain.next_nonce(&node.id) + transfers.iter().filter(|ttx| ttx.from == node.id).count() as u64;
+                            TransferTx::from_node(&node, &to, amount, nonce)
+                        }
+                        (Some(from), Some(to), Some(amount)) => match wallet.account(from) {
+                            Some(account) => {
+                                let address = account.address();
+                                let nonce = blockchain.next_nonce(&address) + transfers.iter().filter(|ttx| ttx.from == address).count() as u64;
+                                TransferTx::new(account, &to, amount, nonce)
+                            }
+                            None => Err("unknown account".into()),
+                        },
+                        _ => Err("usage: SEND <account or node> <account, address or node id> <amount>".into()),
+                    };
+                    // the chain does not check balances, so we do not send more than we know we have
+                    let transfer = transfer.and_then(|ttx| {
+                        if blockchain.balance.get(&ttx.from).copied().unwrap_or(0.0) < ttx.amount {
+                            return Err("insufficient balance".into());
+                        }
+                        Ok(ttx)
+                    });
+                    match transfer {
+                        Ok(ttx) => {
+                            Data::broadcast(&node, &ttx, &mut swarm, &gossip).ok();
+                            transfers.push_back(ttx);
+                        }
+                        Err(e) => println!("[!!] Cannot send: {e}"),
+                    }
+                } else if line == "ASKS" {
+                    for ask in asks.values() {
+                        println!(
+                            "{} asks {} per MiB and epoch for {} to {} bytes over up to {} epochs",
+                            ask.miner_id, ask.price, ask.min_size, ask.max_size, ask.duration
+                        );
+                    }
+                } else if let Some(args) = line.strip_prefix("TAKE ") {
+                    // `TAKE <miner> <path>` stores the file with that miner at the price it asks
+                    let negotiation: Result<(Negotiation, DealTx), Box<dyn Error>> = args
+                        .split_once(' ')
+                        .ok_or_else(|| "usage: TAKE <miner> <path>".into())
+                        .and_then(|(miner_id, path)| {
+                            let ask = asks.get(miner_id).ok_or("no ask from this miner")?;
+                            let request = MemPoolRequest::new(blobs.as_mut(), node.id.to_string(), path, 1)?;
+                            let dtx = DealTx::take(&node, ask, &request)?;
+                            if dtx.amount() > blockchain.balance.get(&node.id).copied().unwrap_or(0.0) {
+                                return Err("insufficient balance".into());
+                            }
+                            let negotiation = Negotiation {
+                                request,
+                                bid: None,
+                                max_price: ask.price,
+                                accepted: vec![],
+                            };
+                            Ok((negotiation, dtx))
+                        });
+                    match negotiation {
+                        Ok((negotiation, dtx)) => {
+                            println!("Request id: {}", negotiation.request.request_id);
+                            Data::broadcast(&node, &dtx, &mut swarm, &gossip).ok();
+                            negotiations.insert(negotiation.request.request_id.clone(), negotiation);
+                        }
+                        Err(e) => println!("[!!] Failed to take ask: {e}"),
+                    }
+                } else if let Some(args) = line.strip_prefix("ACL ") {
+                    // `ACL <peer,peer,..> <path>` stores the file for the listed readers only
+                    let request: Result<MemPoolRequest, Box<dyn Error>> = args
+                        .split_once(' ')
+                        .ok_or_else(|| "usage: ACL <peer,peer,..> <path>".into())
+                        .and_then(|(readers, path)| {
+                            let mut request = MemPoolRequest::new(blobs.as_mut(), node.id.to_string(), path, DEFAULT_REPLICATION_FACTOR)?;
+                            let readers = readers.split(',').map(str::to_string).collect();
+                            request.acl = Some(Acl::new(&node, &request.request_id, readers)?);
+                            Ok(request)
+                        });
+                    match request {
+                        Ok(mut request) => {
+                            offer_fee(&node, &blockchain, &mut request, config.fee);
+                            Data::broadcast(&node, &request, &mut swarm, &gossip).ok();
+                            println!("Request id: {}", request.request_id);
+                            uploads.insert(request.request_id.clone(), request.chunks.clone());
+                            mempool.insert(request, &blockchain).inspect_err(|e| println!("[!!] Request not queued: {e}")).ok();
+                        }
+                        Err(e) => println!("[!!] Failed to restrict {args}: {e}"),
+                    }
+                } else if let Some(path) = line.strip_prefix("ENC ") {
+                    // `ENC <path>` encrypts the file locally, only the owner and key holders can read it
+                    match MemPoolRequest::new_encrypted(blobs.as_mut(), &node, path, DEFAULT_REPLICATION_FACTOR) {
+                        Ok((mut request, key)) => {
+                            offer_fee(&node, &blockchain, &mut request, config.fee);
+                            Data::broadcast(&node, &request, &mut swarm, &gossip).ok();
+                            println!("Request id: {}", request.request_id);
+                            println!("Capability key: {}", hex::encode(key));
+                            uploads.insert(request.request_id.clone(), request.chunks.clone());
+                            mempool.insert(request, &blockchain).inspect_err(|e| println!("[!!] Request not queued: {e}")).ok();
+                        }
+                        Err(e) => println!("[!!] Failed to encrypt {path}: {e}"),
+                    }
+                } else if let Some(mut request) = {
+                    MemPoolRequest::new(blobs.as_mut(), node.id.to_string(), path, replicas)
                         .ok()
                 } {
-                    Data::broadcast(&node, &request, &mut swarm, &topic)
-                        .inspect_err(|e| {})
+                    offer_fee(&node, &blockchain, &mut request, config.fee);
+                    Data::broadcast(&node, &request, &mut swarm, &gossip)
                         .ok();
                     println!("Request id: {}", request.request_id);
-                    mempool.push_back(request);
+                    uploads.insert(request.request_id.clone(), request.chunks.clone());
+                    mempool.insert(request, &blockchain).inspect_err(|e| println!("[!!] Request not queued: {e}")).ok();
                 }
             }
 
             event = swarm.select_next_some() => match event {
+                SwarmEvent::Behaviour(MyBehaviourEvent::Transfer(event)) => {
+                    let finished = match event {
+                        request_response::Event::Message {
+                            peer,
+                            message: request_response::Message::Request { request, channel, .. },
+                            ..
+                        } => {
+                            // our own uploads are served for free, files we hold only for the retrieval fee.
+                            // Restricted files only go to readers on their access list and, while they are
+                            // still being replicated, to miners elected to store them. `peer` is proven by the
+                            // requester's key during the connection handshake.
+                            let upload = mempool.get(&request.request_id);
+                            let acl = blockchain
+                                .find_storage_tx(&request.request_id)
+                                .and_then(|stx| stx.acl.as_ref())
+                                .or(upload.and_then(|request| request.acl.as_ref()));
+                            let allowed = acl.is_none_or(|acl| acl.allows(&peer.to_string()))
+                                || upload.is_some_and(|upload| {
+                                    upload.propose(&peer.to_string(), &blockchain, set_of_nodes.len()).is_ok()
+                                });
+                            let chunks = match uploads.get(&request.request_id) {
+                                Some(chunks) if allowed => Some(chunks),
+                                Some(_) => None,
+                                None if blockchain.holders(&request.request_id).contains(&node.id)
+                                    && request.fee >= RETRIEVAL_FEE_PER_CHUNK =>
+                                {
+                                    blockchain
+                                        .find_storage_tx(&request.request_id)
+                                        .filter(|stx| stx.acl.as_ref().is_none_or(|acl| acl.allows(&peer.to_string())))
+                                        .map(|stx| &stx.chunks)
+                                }
+                                None => None,
+                            };
+                            let (chunk, proof) = match chunks {
+                                Some(chunks) if chunks.get(request.index) == Some(&request.chunk_hash) => (
+                                    blobs.get(&request.chunk_hash).ok(),
+                                    merkle::proof(chunks, request.index),
+                                ),
+                                _ => (None, vec![]),
+                            };
+                            let response = ChunkResponse {
+                                request_id: request.request_id,
+                                index: request.index,
+                                chunk_hash: request.chunk_hash,
+                                chunk,
+                                proof,
+                            };
+                            swarm.behaviour_mut().transfer.send_response(channel, response).ok();
+                            None
+                        }
+                        request_response::Event::Message {
+                            message: request_response::Message::Response { request_id, response },
+                            ..
+                        } if downloads.owns(&request_id) => downloads.on_response(request_id, response),
+                        request_response::Event::Message {
+                            message: request_response::Message::Response { request_id, response },
+                            ..
+                        } => {
+                            if let Some(fetched) = fetches.on_response(request_id, response, blobs.as_mut()) {
+                                finish_fetch(fetched, &node, &mut blockchain, &mut mempool, blobs.as_ref(), set_of_nodes.len());
+                            }
+                            None
+                        }
+                        request_response::Event::OutboundFailure { request_id, .. } if fetches.owns(&request_id) => {
+                            if let Some(fetched) = fetches.on_failure(request_id) {
+                                finish_fetch(fetched, &node, &mut blockchain, &mut mempool, blobs.as_ref(), set_of_nodes.len());
+                            }
+                            None
+                        }
+                        request_response::Event::OutboundFailure { request_id, .. } => downloads.on_failure(request_id),
+                        _ => None,
+                    };
+
+                    if let Some(finished) = finished {
+                        for holder in &finished.failed {
+                            reputation.penalize(&mut swarm, *holder, Offence::FailedToServe);
+                        }
+                        // every verified chunk is paid for, even when the download as a whole failed
+                        for (holder, chunk_hashes) in finished.delivered {
+                            match RetrievalReceipt::new(&node, &finished.request_id, &holder.to_string(), chunk_hashes) {
+                                Ok(receipt) => {
+                                    Data::broadcast(&node, &receipt, &mut swarm, &gossip).ok();
+                                    receipts.push_back(receipt);
+                                }
+                                Err(e) => println!("[!!] Failed to sign receipt: {e}"),
+                            }
+                        }
+                        let file_id = finished.request_id;
+                        finished
+                            .result
+                            .and_then(|file_content| save_retrieved(&node, &blockchain, &mut downloads, &mut pending_shards, &capabilities, &file_id, file_content))
+                            .unwrap_or_else(|e| println!("[!!] Failed to retrieve {file_id}: {e}"));
+                    }
+                    downloads.poll(&mut swarm.behaviour_mut().transfer);
+                    fetches.poll(&mut swarm.behaviour_mut().transfer);
+                }
                 SwarmEvent::Behaviour(MyBehaviourEvent::Mdns(mdns::Event::Discovered(list))) => {
                     for (peer_id, _) in list {
+                        if reputation.is_banned(&peer_id) {
+                            continue;
+                        }
                         // println!("+++ New peer discovered");
                         set_of_nodes.insert(peer_id.to_string());
                         swarm.behaviour_mut().gossipsub.add_explicit_peer(&peer_id);
@@ -141,85 +686,238 @@ async fn main() -> Result<(), Box<dyn Error>> {
                     for (peer_id, _) in list {
                         // println!("--- Peer expired");
                         set_of_nodes.remove(&peer_id.to_string());
+                        gossip.expired(&peer_id);
                         swarm.behaviour_mut().gossipsub.remove_explicit_peer(&peer_id);
                     }
                 }
                 SwarmEvent::Behaviour(MyBehaviourEvent::Gossipsub(gossipsub::Event::Message {
+                    propagation_source,
+                    message_id,
                     message,
-                    ..
                 })) => {
-                    if let Err(e) = (|| -> Result<(), Box<dyn Error>> {
-                        let data = serde_json::from_slice::<Data>(&message.data)?;
-
-                        if !data.verify()? {
-                            return Err("cannot verify received data".into());
+                    // nothing is forwarded before it is validated here
+                    let opened = Data::open(&message.data).and_then(|opened| {
+                        if let Some((_, payload)) = &opened {
+                            gossip.check(&message.topic, message.data.len(), payload)?;
                         }
+                        Ok(opened)
+                    });
+                    // messages of a newer wire version are passed over, only forged or malformed ones count against the peer
+                    let acceptance = match &opened {
+                        Ok(Some((data, _))) if gossip.is_mirror(&message.topic, data.max_version) => {
+                            gossipsub::MessageAcceptance::Ignore
+                        }
+                        Ok(Some((data, payload))) => payload.validate(&data.node_id, &blockchain),
+                        Ok(None) => gossipsub::MessageAcceptance::Ignore,
+                        Err(_) => gossipsub::MessageAcceptance::Reject,
+                    };
+                    let accepted = matches!(acceptance, gossipsub::MessageAcceptance::Accept);
+                    if let Some(source) = message.source {
+                        let id = source.to_string();
+                        let files_held = blockchain.stored.values().filter(|holders| holders.contains(&id)).count();
+                        reputation.heard(&mut swarm, source, files_held);
+                    }
+                    if matches!(acceptance, gossipsub::MessageAcceptance::Reject) {
+                        // honest peers validate before forwarding, so the one that sent it answers for it
+                        let offence = match &opened {
+                            Ok(Some((_, Message::Blockchain(_)))) => Offence::InvalidBlock,
+                            Ok(Some((_, Message::Proof(_)))) => Offence::BadProof,
+                            _ => Offence::InvalidMessage,
+                        };
+                        reputation.penalize(&mut swarm, propagation_source, offence);
+                    }
+                    swarm
+                        .behaviour_mut()
+                        .gossipsub
+                        .report_message_validation_result(&message_id, &propagation_source, acceptance);
+                    let Ok(Some((data, payload))) = opened else { continue };
+                    if let Some(source) = message.source {
+                        gossip.heard(source, data.max_version);
+                    }
+                    if !accepted {
+                        continue;
+                    }
 
-                        let data = data.data;
-                        if let Ok(mut received_blockchain) = serde_json::from_slice::<Blockchain>(&data) {
-                            if received_blockchain.verify() {
-                                blockchain.update(&mut received_blockchain);
-                            }
-                        } else if let Ok(received_request) = serde_json::from_slice::<MemPoolRequest>(&data) {
-                            mempool.push_back(received_request);
-                        }
-                        else if let Ok(received_file) = serde_json::from_slice::<ServeFileTx>(&data) {
-                            let mut fp = File::create(received_file.request_id.to_string() + "_rec")?;
-                            fp.write_all(&received_file.file_content)?;
-                        }
-                        else if let Ok(received_proof) = serde_json::from_slice::<ProofOfStorageTx>(&data) {
-                            // if current node is not in requestid list then skip
-                            if let Some(list) = blockchain.stored.get(&received_proof.request_id) {
-                                if !list.contains(&node.id) {
-                                    return Err("proof is not for me".into());
-                                }
-                            } else {
-                                return Err("invalid received_proof".into());
-                            }
-
-                            println!("{:#?}", received_proof);
-                            // Read the file content
-                            let file_content = match fs::read(received_proof.request_id.clone()) {
-                                Ok(content) => content,
-                                Err(e) => {
-                                    println!("[!!] Failed to read file for request_id {}: {e}", received_proof.request_id);
-                                    return Err("failed to read file".into());
-                                }
-                            };
-                            // Compute the hash for the challenge range
-                            let segment = &file_content[received_proof.start..received_proof.end];
-                            let mut hasher = sha2::Sha256::new();
-                            hasher.update(segment);
-                            let proof_hash = format!("{:x}", hasher.finalize());
-                            // Check if the proof is valid
-                            if proof_hash != received_proof.proof_hash {
-                                return Err("invalid proof".into());
-                            }
-                            println!("[+] Node {} successfully proved the storage for file {}", received_proof.node_id, received_proof.request_id);
-                            *blockchain.balance.entry(received_proof.clone().node_id) .or_insert(0.0) += 0.01;
-                            println!("[+] Balance of Node {} is {}", received_proof.node_id, blockchain.balance.get(&received_proof.node_id).unwrap());
-                        }
-                        else if let Ok(received_query) = serde_json::from_slice::<QueryTx>(&data) {
-                            if let Some(nodeid) = blockchain.stored.get(&received_query.request_id) {
-                                if !nodeid.contains(&node.id) {
-                                    return Err("queried file is not stored by me".into());
-                                }
-                                serving_q.push_back(received_query.request_id);
-                            }
+                    let handled = match payload {
+                        Message::Blockchain(received) => {
+                            handlers::blockchain(received, &mut blockchain);
+                            Ok(())
                         }
-                        else {
-                            return Err("invalid received_signed_data".into());
+                        Message::Request(request) => {
+                            handlers::request(request, &mut mempool, &blockchain);
+                            Ok(())
                         }
-                        Ok(())
-                    })() {
+                        Message::Receipt(receipt) => handlers::receipt(receipt, &mut receipts),
+                        Message::Lifecycle(ltx) => handlers::lifecycle(ltx, &mut lifecycle),
+                        Message::Transfer(ttx) => {
+                            handlers::transfer(ttx, &mut transfers);
+                            Ok(())
+                        }
+                        Message::Capacity(ctx) => handlers::capacity(ctx, &mut declarations),
+                        Message::Ask(received) => handlers::ask(received, &mut asks),
+                        Message::Bid(bid) => handlers::bid(bid, &node, &ask, &blockchain, blobs.as_ref(), &mut offered, &mut swarm, &gossip),
+                        Message::Deal(dtx) => handlers::deal(dtx, &node, &ask, &mut negotiations, &mut deals, &mut swarm, &gossip),
+                        Message::Proof(proof) => handlers::proof(
+                            proof,
+                            message.source,
+                            &node,
+                            &mut blockchain,
+                            blobs.as_ref(),
+                            &mut replica_monitor,
+                            &mut reputation,
+                            &mut swarm,
+                        ),
+                    };
+                    if let Err(_e) = handled {
                         // println!("[!!] {e}");
                     }
                 }
+                SwarmEvent::Behaviour(MyBehaviourEvent::Kad(kad::Event::RoutingUpdated { peer, old_peer, .. })) => {
+                    if let Some(old_peer) = old_peer {
+                        set_of_nodes.remove(&old_peer.to_string());
+                        gossip.expired(&old_peer);
+                    }
+                    if !reputation.is_banned(&peer) {
+                        set_of_nodes.insert(peer.to_string());
+                    }
+                }
+                SwarmEvent::Behaviour(MyBehaviourEvent::Kad(kad::Event::OutboundQueryProgressed {
+                    id,
+                    result: kad::QueryResult::GetProviders(result),
+                    step,
+                    ..
+                })) => {
+                    let Some(request_id) = providers.lookup(id, step.last) else { continue };
+                    if let Ok(kad::GetProvidersOk::FoundProviders { providers: found, .. }) = result {
+                        let local = *swarm.local_peer_id();
+                        downloads.add_sources(&request_id, found.into_iter().filter(|peer| *peer != local && !reputation.is_banned(peer)));
+                    }
+                    if step.last && downloads.drop_sourceless(&request_id) {
+                        println!("[!!] Cannot retrieve {request_id}: no holder found on-chain or in the DHT");
+                    }
+                    downloads.poll(&mut swarm.behaviour_mut().transfer);
+                }
+                SwarmEvent::ConnectionEstablished {
+                    peer_id,
+                    endpoint: ConnectedPoint::Listener { send_back_addr, .. },
+                    ..
+                } if send_back_addr.iter().any(|protocol| protocol == Protocol::QuicV1) => {
+                    // QUIC peers dial from the socket they listen on, so unlike TCP the address an
+                    // inbound peer comes from is one it can be reached at
+                    swarm.behaviour_mut().kad.add_address(&peer_id, send_back_addr);
+                }
                 SwarmEvent::NewListenAddr { address, .. } => {
                     println!("[#] Listening on {address}");
                 }
                 _ => {}
             }
         }
+
+        store
+            .save(&blockchain, &mempool, &uploads)
+            .inspect_err(|e| println!("[!!] Failed to save state: {e}"))
+            .ok();
+    }
+}
+
+/// Offers the configured fee on one of our uploads. If that fails the request goes out without a fee.
+fn offer_fee(node: &Node, blockchain: &Blockchain, request: &mut MemPoolRequest, fee: f64) {
+    // the chain does not check balances, so we only offer what we know we have
+    let total = fee * request.replicas as f64;
+    if fee > 0.0 && total > blockchain.balance.get(&node.id).copied().unwrap_or(0.0) {
+        println!(
+            "[!!] Cannot pay a fee of {total} for {}, offering none",
+            request.request_id
+        );
+        return;
+    }
+    if let Err(e) = request.sign(node, fee) {
+        println!("[!!] Failed to sign the fee of {}: {e}", request.request_id);
     }
 }
+
+/// Mines a request once all of its chunks were fetched from the uploader. If the uploader failed or
+/// refused to serve one, the request goes back into the mempool so that it can be tried again.
+fn finish_fetch(
+    fetched: Fetched,
+    node: &Node,
+    blockchain: &mut Blockchain,
+    mempool: &mut Mempool,
+    blobs: &dyn BlobStore,
+    total_nodes: usize,
+) {
+    match fetched {
+        Fetched::Complete(request) => {
+            request
+                .mine(node, blockchain, total_nodes, blobs)
+                .inspect_err(|e| {
+                    println!(
+                        "[!!] Fetched {} but cannot mine it: {e}",
+                        request.request_id
+                    )
+                })
+                .ok();
+        }
+        Fetched::Abandoned(request) => {
+            println!(
+                "[!!] Could not fetch {} from its uploader",
+                request.request_id
+            );
+            mempool.insert(request, blockchain).ok();
+        }
+    }
+}
+
+/// Writes a finished retrieval to `<id>_rec`, rebuilding sharded files once enough shards are in
+/// and decrypting files we were given a capability key for
+fn save_retrieved(
+    node: &Node,
+    blockchain: &Blockchain,
+    downloads: &mut DownloadManager,
+    pending_shards: &mut HashMap<String, Vec<Option<Vec<u8>>>>,
+    capabilities: &HashMap<String, [u8; 32]>,
+    request_id: &str,
+    file_content: Vec<u8>,
+) -> Result<(), Box<dyn Error>> {
+    let shard = blockchain
+        .find_storage_tx(request_id)
+        .and_then(|stx| stx.shard.clone());
+    if let Some(info) = shard {
+        // shards still coming in after the file was rebuilt are not needed
+        let Some(slots) = pending_shards.get_mut(&info.file_id) else {
+            return Ok(());
+        };
+        *slots
+            .get_mut(info.index)
+            .ok_or("shard index is out of range for its file")? = Some(file_content);
+        if slots.iter().flatten().count() >= info.data_shards {
+            let file_content = erasure::reconstruct(slots, &info)?;
+            let mut fp = File::create(info.file_id.to_string() + "_rec")?;
+            fp.write_all(&file_content)?;
+            pending_shards.remove(&info.file_id);
+            for index in 0..info.data_shards + info.parity_shards {
+                downloads.cancel(&erasure::shard_request_id(&info.file_id, index));
+            }
+            println!("[+] Rebuilt file {} from its shards", info.file_id);
+        }
+        return Ok(());
+    }
+
+    let file_content = match capabilities.get(request_id) {
+        Some(key) => encryption::decrypt(key, &file_content)?,
+        // the owner re-derives the key of its own uploads, plain ones do not decrypt and stay as is
+        None if blockchain
+            .find_storage_tx(request_id)
+            .is_some_and(|stx| stx.owner == node.id) =>
+        {
+            encryption::file_key(node, request_id)
+                .and_then(|key| encryption::decrypt(&key, &file_content))
+                .unwrap_or(file_content)
+        }
+        None => file_content,
+    };
+    let mut fp = File::create(request_id.to_string() + "_rec")?;
+    fp.write_all(&file_content)?;
+    println!("[+] Retrieved file {request_id}");
+    Ok(())
+}
@@ -0,0 +1,282 @@
+use std::error::Error;
+
+use crate::blockchain::Blockchain;
+use crate::mempool::MemPoolRequest;
+use crate::node::{Node, verify_signature};
+use crate::transaction::{Ask, Bid, DealTx};
+
+const MIB: f64 = (1 << 20) as f64;
+
+impl Ask {
+    fn signing_bytes(&self) -> Vec<u8> {
+        serde_json::to_vec(&(
+            &self.miner_id,
+            self.price,
+            self.min_size,
+            self.max_size,
+            self.duration,
+        ))
+        .unwrap_or_default()
+    }
+
+    pub fn new(
+        node: &Node,
+        price: f64,
+        min_size: u64,
+        max_size: u64,
+        duration: u64,
+    ) -> Result<Self, Box<dyn Error>> {
+        let mut ask = Ask {
+            miner_id: node.id.clone(),
+            price,
+            min_size,
+            max_size,
+            duration,
+            signature: vec![],
+            public_key: node.public_key.clone(),
+        };
+        ask.signature = node.private_key.sign(&ask.signing_bytes())?;
+
+        Ok(ask)
+    }
+
+    pub fn verify(&self) -> Result<bool, Box<dyn Error>> {
+        verify_signature(
+            &self.miner_id,
+            &self.public_key,
+            &self.signing_bytes(),
+            &self.signature,
+        )
+    }
+
+    pub fn fits(&self, file_size: u64, duration: u64) -> bool {
+        (self.min_size..=self.max_size).contains(&file_size) && duration <= self.duration
+    }
+}
+
+impl Bid {
+    fn signing_bytes(&self) -> Vec<u8> {
+        serde_json::to_vec(&(
+            &self.client_id,
+            &self.request_id,
+            self.file_size,
+            self.replicas,
+            self.duration,
+            self.max_price,
+        ))
+        .unwrap_or_default()
+    }
+
+    pub fn new(
+        node: &Node,
+        request: &MemPoolRequest,
+        max_price: f64,
+    ) -> Result<Self, Box<dyn Error>> {
+        let mut bid = Bid {
+            client_id: node.id.clone(),
+            request_id: request.request_id.clone(),
+            file_size: request.file_size as u64,
+            replicas: request.replicas,
+            duration: request.duration,
+            max_price,
+            signature: vec![],
+            public_key: node.public_key.clone(),
+        };
+        bid.signature = node.private_key.sign(&bid.signing_bytes())?;
+
+        Ok(bid)
+    }
+
+    /// Most the client pays, if every replica is sold at the highest price it bid
+    pub fn max_amount(&self) -> f64 {
+        self.max_price * self.file_size as f64 / MIB * self.duration as f64 * self.replicas as f64
+    }
+
+    pub fn verify(&self) -> Result<bool, Box<dyn Error>> {
+        verify_signature(
+            &self.client_id,
+            &self.public_key,
+            &self.signing_bytes(),
+            &self.signature,
+        )
+    }
+}
+
+impl DealTx {
+    /// Both parties sign the same terms
+    fn signing_bytes(&self) -> Vec<u8> {
+        serde_json::to_vec(&(
+            &self.request_id,
+            &self.client_id,
+            &self.miner_id,
+            self.file_size,
+            self.duration,
+            self.price,
+        ))
+        .unwrap_or_default()
+    }
+
+    /// Miner-signed answer to a bid, at the price of the miner's ask
+    pub fn offer(node: &Node, bid: &Bid, ask: &Ask) -> Result<Self, Box<dyn Error>> {
+        if !ask.fits(bid.file_size, bid.duration) || ask.price > bid.max_price {
+            return Err("bid does not match the ask".into());
+        }
+        let mut dtx = DealTx {
+            request_id: bid.request_id.clone(),
+            client_id: bid.client_id.clone(),
+            miner_id: node.id.clone(),
+            file_size: bid.file_size,
+            duration: bid.duration,
+            price: ask.price,
+            client_signature: vec![],
+            client_public_key: vec![],
+            miner_signature: vec![],
+            miner_public_key: vec![],
+        };
+        dtx.sign(node)?;
+
+        Ok(dtx)
+    }
+
+    /// Client-signed acceptance of an ask, the miner still has to countersign it
+    pub fn take(node: &Node, ask: &Ask, request: &MemPoolRequest) -> Result<Self, Box<dyn Error>> {
+        if !ask.fits(request.file_size as u64, request.duration) {
+            return Err("file does not fit the ask".into());
+        }
+        let mut dtx = DealTx {
+            request_id: request.request_id.clone(),
+            client_id: node.id.clone(),
+            miner_id: ask.miner_id.clone(),
+            file_size: request.file_size as u64,
+            duration: request.duration,
+            price: ask.price,
+            client_signature: vec![],
+            client_public_key: vec![],
+            miner_signature: vec![],
+            miner_public_key: vec![],
+        };
+        dtx.sign(node)?;
+
+        Ok(dtx)
+    }
+
+    /// Adds the signature of whichever party `node` is
+    pub fn sign(&mut self, node: &Node) -> Result<(), Box<dyn Error>> {
+        let signature = node.private_key.sign(&self.signing_bytes())?;
+        if node.id == self.client_id {
+            self.client_signature = signature;
+            self.client_public_key = node.public_key.clone();
+        } else if node.id == self.miner_id {
+            self.miner_signature = signature;
+            self.miner_public_key = node.public_key.clone();
+        } else {
+            return Err("node is not a party to the deal".into());
+        }
+        Ok(())
+    }
+
+    pub fn is_complete(&self) -> bool {
+        !self.client_signature.is_empty() && !self.miner_signature.is_empty()
+    }
+
+    /// Checks the signatures present, both are needed before the deal can go on-chain
+    pub fn verify(&self) -> Result<bool, Box<dyn Error>> {
+        let bytes = self.signing_bytes();
+        Ok((self.client_signature.is_empty()
+            || verify_signature(
+                &self.client_id,
+                &self.client_public_key,
+                &bytes,
+                &self.client_signature,
+            )?)
+            && (self.miner_signature.is_empty()
+                || verify_signature(
+                    &self.miner_id,
+                    &self.miner_public_key,
+                    &bytes,
+                    &self.miner_signature,
+                )?))
+    }
+
+    /// Full price of the deal, locked in escrow when it is recorded
+    pub fn amount(&self) -> f64 {
+        self.price * self.file_size as f64 / MIB * self.duration as f64
+    }
+
+    /// Checks that both parties signed and that the deal can still be recorded on `blockchain`
+    pub fn check(&self, blockchain: &Blockchain) -> Result<(), Box<dyn Error>> {
+        if !self.is_complete() || !self.verify()? {
+            return Err("deal is not signed by both parties".into());
+        }
+        if self.client_id == self.miner_id {
+            return Err("client cannot make a deal with itself".into());
+        }
+        if !self.price.is_finite() || self.price < 0.0 {
+            return Err("invalid price".into());
+        }
+        if blockchain.find_storage_tx(&self.request_id).is_some() {
+            return Err("deals must be recorded before the file is stored".into());
+        }
+        if blockchain
+            .market_deals
+            .get(&self.request_id)
+            .is_some_and(|deals| deals.iter().any(|dtx| dtx.miner_id == self.miner_id))
+        {
+            return Err("deal already recorded".into());
+        }
+        if self.file_size > blockchain.free_space(&self.miner_id) {
+            return Err("miner has not declared enough free capacity".into());
+        }
+        Ok(())
+    }
+}
+
+/// A file of ours waiting for miners to agree to store it. It stays out of the mempool, and its
+/// chunks are not served, until its deals are on-chain.
+pub struct Negotiation {
+    pub request: MemPoolRequest,
+    /// Gossiped until enough miners answered, `None` when we took an ask directly
+    pub bid: Option<Bid>,
+    pub max_price: f64,
+    pub accepted: Vec<DealTx>,
+}
+
+impl Negotiation {
+    /// Countersigns an offer that matches our terms while miners are still missing. Returns the
+    /// deal to record, if any.
+    pub fn accept(
+        &mut self,
+        node: &Node,
+        mut dtx: DealTx,
+    ) -> Result<Option<DealTx>, Box<dyn Error>> {
+        if !dtx.verify()?
+            || dtx.miner_signature.is_empty()
+            || dtx.client_id != node.id
+            || dtx.file_size != self.request.file_size as u64
+            || dtx.duration != self.request.duration
+            || dtx.price > self.max_price
+            || self.accepted.len() >= self.request.replicas
+            || self
+                .accepted
+                .iter()
+                .any(|other| other.miner_id == dtx.miner_id)
+        {
+            return Ok(None);
+        }
+        if dtx.client_signature.is_empty() {
+            dtx.sign(node)?;
+        }
+        self.accepted.push(dtx.clone());
+
+        Ok(Some(dtx))
+    }
+
+    /// Every replica has a deal on-chain, so the file can be handed to its miners
+    pub fn is_settled(&self, blockchain: &Blockchain) -> bool {
+        let recorded = blockchain.market_deals.get(&self.request.request_id);
+        self.accepted.len() == self.request.replicas
+            && self.accepted.iter().all(|dtx| {
+                recorded.is_some_and(|deals| deals.iter().any(|d| d.miner_id == dtx.miner_id))
+            })
+    }
+}
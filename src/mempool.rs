@@ -1,22 +1,50 @@
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
-use std::io::prelude::*;
-use std::{error::Error, fs, fs::File};
+use std::collections::HashMap;
+use std::{error::Error, fs};
 use uuid::Uuid;
 
+use crate::blob_store::BlobStore;
 use crate::block::Block;
-use crate::blockchain::Blockchain;
-use crate::node::Node;
-use crate::randomized_election::is_elected;
-use crate::transaction::StorageTx;
+use crate::blockchain::{Blockchain, DEFAULT_DEAL_EPOCHS};
+use crate::chunking;
+use crate::encryption;
+use crate::erasure;
+use crate::merkle;
+use crate::node::{Node, verify_signature};
+use crate::randomized_election::is_elected_with_room;
+use crate::transaction::{Acl, RepairTx, ShardInfo, StorageTx};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct MemPoolRequest {
     pub node_id: String,
     pub request_id: String,
-    pub file_content: Vec<u8>,
     pub file_hash: String,
     pub file_size: usize,
+    pub merkle_root: String,
+    pub replicas: usize,
+    pub chunks: Vec<String>,
+    #[serde(default)]
+    pub repair: Option<RepairTx>,
+    #[serde(default)]
+    pub shard: Option<ShardInfo>,
+    #[serde(default)]
+    pub acl: Option<Acl>,
+    /// Epochs the file is stored for, counted from when its first replica is mined
+    #[serde(default = "default_duration")]
+    pub duration: u64,
+    /// Paid by the uploader to every miner of a replica, higher fees are mined first
+    #[serde(default)]
+    pub fee: f64,
+    /// Uploader signature committing to the fee, unsigned requests pay none
+    #[serde(default)]
+    pub signature: Vec<u8>,
+    #[serde(default)]
+    pub public_key: Vec<u8>,
+}
+
+fn default_duration() -> u64 {
+    DEFAULT_DEAL_EPOCHS
 }
 
 fn compute_file_hash(file_data: &[u8]) -> String {
@@ -25,67 +53,608 @@ fn compute_file_hash(file_data: &[u8]) -> String {
     format!("{:x}", hasher.finalize())
 }
 
+/// What an uploader signs to commit to a fee, rebuilt from the storage tx when the block is
+/// verified. The fields go in a JSON array so that no other terms sign the same bytes.
+fn fee_signing_bytes(
+    request_id: &str,
+    file_hash: &str,
+    merkle_root: &str,
+    replicas: usize,
+    fee: f64,
+) -> Vec<u8> {
+    serde_json::to_vec(&(request_id, file_hash, merkle_root, replicas, fee)).unwrap_or_default()
+}
+
+impl StorageTx {
+    /// Checks that the owner agreed to pay the fee, storage without a fee needs no signature
+    pub fn verify_fee(&self) -> Result<bool, Box<dyn Error>> {
+        if self.fee == 0.0 {
+            return Ok(true);
+        }
+        let bytes = fee_signing_bytes(
+            &self.request_id,
+            &self.file_hash,
+            &self.merkle_root,
+            self.replicas,
+            self.fee,
+        );
+        verify_signature(
+            &self.owner,
+            &self.fee_public_key,
+            &bytes,
+            &self.fee_signature,
+        )
+    }
+}
+
 impl MemPoolRequest {
-    pub fn mine(
+    fn signing_bytes(&self) -> Vec<u8> {
+        fee_signing_bytes(
+            &self.request_id,
+            &self.file_hash,
+            &self.merkle_root,
+            self.replicas,
+            self.fee,
+        )
+    }
+
+    /// Offers `fee` to every miner of a replica, the request is left as it was if signing fails
+    pub fn sign(&mut self, node: &Node, fee: f64) -> Result<(), Box<dyn Error>> {
+        let signing_bytes = fee_signing_bytes(
+            &self.request_id,
+            &self.file_hash,
+            &self.merkle_root,
+            self.replicas,
+            fee,
+        );
+        self.signature = node.private_key.sign(&signing_bytes)?;
+        self.public_key = node.public_key.clone();
+        self.fee = fee;
+        Ok(())
+    }
+
+    /// Checks the uploader's signature, unsigned requests are fine as long as they offer no fee
+    pub fn verify(&self) -> Result<bool, Box<dyn Error>> {
+        if self.signature.is_empty() {
+            return Ok(self.fee == 0.0);
+        }
+        verify_signature(
+            &self.node_id,
+            &self.public_key,
+            &self.signing_bytes(),
+            &self.signature,
+        )
+    }
+
+    /// Checks that the request can still be mined on top of `blockchain`
+    pub fn check(&self, blockchain: &Blockchain) -> Result<(), Box<dyn Error>> {
+        if !self.verify()? || !self.fee.is_finite() || self.fee < 0.0 {
+            return Err("invalid fee".into());
+        }
+        if self.repair.is_none()
+            && blockchain.holders(&self.request_id).len() >= blockchain.replicas(&self.request_id)
+        {
+            return Err("file is fully replicated".into());
+        }
+        if blockchain.has_ended(&self.request_id) {
+            return Err("storage deal has ended".into());
+        }
+        if let Some(repair) = &self.repair
+            && (repair.request_id != self.request_id
+                || repair.holder_id != self.node_id
+                || !repair.verify(blockchain)?)
+        {
+            return Err("invalid repair".into());
+        }
+        Ok(())
+    }
+
+    /// Builds the block `miner_id` would mine for the request, failing if the block is invalid or
+    /// the miner is not elected. Nothing is fetched or committed yet.
+    pub fn propose(
         &self,
-        node: &Node,
-        blockchain: &mut Blockchain,
+        miner_id: &str,
+        blockchain: &Blockchain,
         total_nodes: usize,
     ) -> Result<Block, Box<dyn Error>> {
-        if blockchain.search_transaction(&self.request_id) {
-            return Err("Request already served".into());
+        if self.node_id == miner_id {
+            return Err("Requesting node is same as miner node".into());
         }
 
-        if self.node_id == node.id {
-            return Err("Requesting node is same as miner node".into());
+        if merkle::root(&self.chunks) != self.merkle_root {
+            return Err("Chunk list does not match its merkle root".into());
         }
 
-        let block = Block {
-            previous_hash: Some(blockchain.chain.last().unwrap().hash.clone()),
-            mtx: None,
-            stx: Some(StorageTx {
-                miner_id: node.id.clone(),
-                request_id: self.request_id.clone(),
-                file_hash: self.file_hash.clone(),
-                file_size: self.file_size,
-            }),
-            hash: "".to_string(),
+        let replaces = self
+            .repair
+            .as_ref()
+            .map_or(vec![], |repair| repair.dropped.clone());
+
+        // only the uploader may restrict a new file, repairs carry over the list already on-chain
+        if self.repair.is_none()
+            && let Some(acl) = &self.acl
+            && acl.owner != self.node_id
+        {
+            return Err("Access list is not signed by the uploader".into());
         }
-        .calculate_hash();
 
-        if !is_elected(&node.id, &block.hash.clone(), total_nodes as u64) {
+        // later replicas and repairs inherit the owner and end of the deal from the first one
+        let (owner, end_epoch) = match blockchain.find_storage_tx(&self.request_id) {
+            Some(first) => (first.owner.clone(), first.end_epoch),
+            None => (
+                self.node_id.clone(),
+                Some(blockchain.epoch() + self.duration),
+            ),
+        };
+
+        let stx = StorageTx {
+            miner_id: miner_id.to_string(),
+            request_id: self.request_id.clone(),
+            file_hash: self.file_hash.clone(),
+            file_size: self.file_size,
+            merkle_root: self.merkle_root.clone(),
+            replicas: self.replicas,
+            chunks: self.chunks.clone(),
+            replaces,
+            repair: self.repair.clone(),
+            shard: self.shard.clone(),
+            acl: self.acl.clone(),
+            owner,
+            end_epoch,
+            // repairs are paid by nobody, the fee only ever comes from the uploader
+            fee: if self.repair.is_none() { self.fee } else { 0.0 },
+            fee_signature: if self.repair.is_none() {
+                self.signature.clone()
+            } else {
+                vec![]
+            },
+            fee_public_key: if self.repair.is_none() {
+                self.public_key.clone()
+            } else {
+                vec![]
+            },
+        };
+        let block = Block::new(&blockchain.chain.last().unwrap().hash, stx.into());
+
+        blockchain
+            .verify_block(&block)
+            .map_err(|e| format!("Request cannot be served: {e}"))?;
+
+        // miners that signed a market deal for the file store it without being elected
+        let has_deal = blockchain
+            .market_deals
+            .get(&self.request_id)
+            .is_some_and(|deals| deals.iter().any(|dtx| dtx.miner_id == miner_id));
+        if !has_deal
+            && !is_elected_with_room(
+                miner_id,
+                &block.hash,
+                total_nodes as u64,
+                blockchain.room(miner_id),
+            )
+        {
             return Err("Not eligible to propose a block".into());
         }
 
+        Ok(block)
+    }
+
+    /// Commits the request once all of its chunks have been fetched from the uploader
+    pub fn mine(
+        &self,
+        node: &Node,
+        blockchain: &mut Blockchain,
+        total_nodes: usize,
+        blobs: &dyn BlobStore,
+    ) -> Result<Block, Box<dyn Error>> {
+        let block = self.propose(&node.id, blockchain, total_nodes)?;
+
+        let file_content = chunking::read_chunks(blobs, &self.chunks)?;
+        if compute_file_hash(&file_content) != self.file_hash {
+            return Err("File content does not match its hash".into());
+        }
+
         blockchain.add_block(block.clone());
-        blockchain
-            .stored
-            .entry(self.request_id.clone())
-            .or_insert(vec![])
-            .push(node.id.clone());
 
         println!("{:#?}", blockchain.stored);
 
-        // store the file_content locally
-        let mut fp = File::create(self.request_id.to_string())?;
-        fp.write_all(&self.file_content)?;
-
         println!("miner {} has mined request {}", self.request_id, node.id);
 
         Ok(block)
     }
 
-    pub fn new(node_id: String, file_path: &str) -> Result<Self, Box<dyn Error>> {
+    pub fn new(
+        blobs: &mut dyn BlobStore,
+        node_id: String,
+        file_path: &str,
+        replicas: usize,
+    ) -> Result<Self, Box<dyn Error>> {
         let file_content = fs::read(file_path)?;
-        let file_hash = compute_file_hash(&file_content);
-        let file_size = file_content.len();
+        Self::from_content(
+            blobs,
+            node_id,
+            Uuid::new_v4().to_string(),
+            &file_content,
+            replicas,
+        )
+    }
+
+    /// Encrypts the file before it leaves this node, only the ciphertext and its hash are gossiped
+    /// and committed. Returns the request along with the capability key needed to read it back.
+    pub fn new_encrypted(
+        blobs: &mut dyn BlobStore,
+        node: &Node,
+        file_path: &str,
+        replicas: usize,
+    ) -> Result<(Self, [u8; 32]), Box<dyn Error>> {
+        let request_id = Uuid::new_v4().to_string();
+        let key = encryption::file_key(node, &request_id)?;
+        let file_content = encryption::encrypt(&key, &fs::read(file_path)?)?;
+
+        Ok((
+            Self::from_content(blobs, node.id.clone(), request_id, &file_content, replicas)?,
+            key,
+        ))
+    }
+
+    /// Keeps the content in the local chunk store, from where elected miners fetch it, and
+    /// describes it by metadata only
+    fn from_content(
+        blobs: &mut dyn BlobStore,
+        node_id: String,
+        request_id: String,
+        file_content: &[u8],
+        replicas: usize,
+    ) -> Result<Self, Box<dyn Error>> {
+        let chunks = chunking::write_chunks(blobs, file_content)?;
 
         Ok(MemPoolRequest {
             node_id,
-            request_id: Uuid::new_v4().to_string(),
-            file_content,
-            file_hash,
-            file_size,
+            request_id,
+            file_hash: compute_file_hash(file_content),
+            file_size: file_content.len(),
+            merkle_root: merkle::root(&chunks),
+            replicas,
+            chunks,
+            repair: None,
+            shard: None,
+            acl: None,
+            duration: DEFAULT_DEAL_EPOCHS,
+            fee: 0.0,
+            signature: vec![],
+            public_key: vec![],
+        })
+    }
+
+    /// Erasure-codes the file into `data_shards + parity_shards` requests, one per shard, each of
+    /// which is mined by a different miner
+    pub fn new_sharded(
+        blobs: &mut dyn BlobStore,
+        node_id: String,
+        file_path: &str,
+        data_shards: usize,
+        parity_shards: usize,
+    ) -> Result<Vec<Self>, Box<dyn Error>> {
+        let file_content = fs::read(file_path)?;
+        let file_id = Uuid::new_v4().to_string();
+        let file_hash = compute_file_hash(&file_content);
+
+        let shards = erasure::encode(&file_content, data_shards, parity_shards)?;
+        let mut requests = vec![];
+        for (index, shard) in shards.iter().enumerate() {
+            let request_id = erasure::shard_request_id(&file_id, index);
+            let mut request = Self::from_content(blobs, node_id.clone(), request_id, shard, 1)?;
+            request.shard = Some(ShardInfo {
+                file_id: file_id.clone(),
+                index,
+                data_shards,
+                parity_shards,
+                file_size: file_content.len(),
+                file_hash: file_hash.clone(),
+            });
+            requests.push(request);
+        }
+
+        Ok(requests)
+    }
+
+    /// Re-offers a file this node holds so that new miners replace the `dropped` holders
+    pub fn new_repair(
+        blobs: &dyn BlobStore,
+        node: &Node,
+        blockchain: &Blockchain,
+        request_id: &str,
+        dropped: Vec<String>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let stx = blockchain
+            .find_storage_tx(request_id)
+            .ok_or("Unknown request id")?;
+        let file_content = chunking::read_file(blobs, blockchain, request_id)?;
+        if compute_file_hash(&file_content) != stx.file_hash {
+            return Err("Local replica is corrupted".into());
+        }
+
+        Ok(MemPoolRequest {
+            node_id: node.id.clone(),
+            request_id: request_id.to_string(),
+            file_hash: stx.file_hash.clone(),
+            file_size: stx.file_size,
+            merkle_root: stx.merkle_root.clone(),
+            replicas: stx.replicas,
+            chunks: stx.chunks.clone(),
+            repair: Some(RepairTx::new(node, request_id, dropped)?),
+            shard: stx.shard.clone(),
+            acl: stx.acl.clone(),
+            duration: DEFAULT_DEAL_EPOCHS,
+            fee: 0.0,
+            signature: vec![],
+            public_key: vec![],
         })
     }
 }
+
+/// A request waiting in the mempool, with its place in line and the bytes it takes up
+struct Entry {
+    request: MemPoolRequest,
+    seq: u64,
+    size: usize,
+}
+
+/// Requests waiting to be mined, at most one per request id. Higher fees are mined first and the
+/// lowest ones are dropped once the pool outgrows `max_bytes`.
+pub struct Mempool {
+    entries: HashMap<String, Entry>,
+    bytes: usize,
+    max_bytes: usize,
+    seq: u64,
+    /// Bumped on every change, so the pool is only written to disk when it changed
+    generation: u64,
+}
+
+impl Mempool {
+    pub fn new(max_bytes: usize) -> Self {
+        Mempool {
+            entries: HashMap::new(),
+            bytes: 0,
+            max_bytes,
+            seq: 0,
+            generation: 0,
+        }
+    }
+
+    /// Rebuilds the pool from saved requests, dropping those the chain has moved past
+    pub fn load(requests: Vec<MemPoolRequest>, max_bytes: usize, blockchain: &Blockchain) -> Self {
+        let mut mempool = Mempool::new(max_bytes);
+        for request in requests {
+            mempool.insert(request, blockchain).ok();
+        }
+        mempool
+    }
+
+    /// Adds a request that is still valid on `blockchain`. A request already in the pool is only
+    /// replaced by one offering a higher fee.
+    pub fn insert(
+        &mut self,
+        request: MemPoolRequest,
+        blockchain: &Blockchain,
+    ) -> Result<(), Box<dyn Error>> {
+        request.check(blockchain)?;
+        if let Some(entry) = self.entries.get(&request.request_id)
+            && entry.request.fee >= request.fee
+        {
+            return Err("request is already in the mempool".into());
+        }
+
+        let request_id = request.request_id.clone();
+        let size = serde_json::to_vec(&request)?.len();
+        self.remove(&request_id);
+        self.seq += 1;
+        self.generation += 1;
+        self.bytes += size;
+        self.entries.insert(
+            request_id.clone(),
+            Entry {
+                request,
+                seq: self.seq,
+                size,
+            },
+        );
+
+        // the cheapest and, among equal fees, the newest requests go first
+        while self.bytes > self.max_bytes {
+            let Some(evicted) = self
+                .entries
+                .iter()
+                .min_by(|(_, a), (_, b)| {
+                    a.request
+                        .fee
+                        .total_cmp(&b.request.fee)
+                        .then(b.seq.cmp(&a.seq))
+                })
+                .map(|(id, _)| id.clone())
+            else {
+                break;
+            };
+            self.remove(&evicted);
+            if evicted == request_id {
+                return Err("mempool is full of requests with higher fees".into());
+            }
+        }
+        Ok(())
+    }
+
+    pub fn remove(&mut self, request_id: &str) -> Option<MemPoolRequest> {
+        let entry = self.entries.remove(request_id)?;
+        self.bytes -= entry.size;
+        self.generation += 1;
+        Some(entry.request)
+    }
+
+    /// Drops requests that can no longer be mined, once replicated, expired or unpaid
+    pub fn prune(&mut self, blockchain: &Blockchain) {
+        let stale: Vec<String> = self
+            .entries
+            .values()
+            .filter(|entry| entry.request.check(blockchain).is_err())
+            .map(|entry| entry.request.request_id.clone())
+            .collect();
+        for request_id in stale {
+            self.remove(&request_id);
+        }
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    pub fn get(&self, request_id: &str) -> Option<&MemPoolRequest> {
+        self.entries.get(request_id).map(|entry| &entry.request)
+    }
+
+    /// Highest fee first, oldest first among equal fees
+    pub fn by_priority(&self) -> Vec<&MemPoolRequest> {
+        let mut entries: Vec<&Entry> = self.entries.values().collect();
+        entries.sort_by(|a, b| {
+            b.request
+                .fee
+                .total_cmp(&a.request.fee)
+                .then(a.seq.cmp(&b.seq))
+        });
+        entries.into_iter().map(|entry| &entry.request).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::DEFAULT_REPLICATION_FACTOR;
+    use libp2p::identity::Keypair;
+
+    fn request(node: &Node, request_id: &str, fee: f64) -> MemPoolRequest {
+        let mut request = MemPoolRequest {
+            node_id: node.id.clone(),
+            request_id: request_id.to_string(),
+            file_hash: "hash".to_string(),
+            file_size: 1,
+            merkle_root: merkle::root(&[]),
+            replicas: 1,
+            chunks: vec![],
+            repair: None,
+            shard: None,
+            acl: None,
+            duration: DEFAULT_DEAL_EPOCHS,
+            fee: 0.0,
+            signature: vec![],
+            public_key: vec![],
+        };
+        request.sign(node, fee).unwrap();
+        request
+    }
+
+    fn setup() -> (Node, Blockchain) {
+        let node = Node::new(Keypair::generate_ed25519());
+        (node, Blockchain::new_with_genesis_block())
+    }
+
+    fn ids(mempool: &Mempool) -> Vec<&str> {
+        mempool
+            .by_priority()
+            .into_iter()
+            .map(|request| request.request_id.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn higher_fees_first_then_oldest() {
+        let (node, blockchain) = setup();
+        let mut mempool = Mempool::new(usize::MAX);
+        for (id, fee) in [("a", 1.0), ("b", 3.0), ("c", 1.0), ("d", 2.0)] {
+            mempool
+                .insert(request(&node, id, fee), &blockchain)
+                .unwrap();
+        }
+        assert_eq!(ids(&mempool), ["b", "d", "a", "c"]);
+    }
+
+    #[test]
+    fn duplicate_only_replaced_by_higher_fee() {
+        let (node, blockchain) = setup();
+        let mut mempool = Mempool::new(usize::MAX);
+        mempool
+            .insert(request(&node, "a", 2.0), &blockchain)
+            .unwrap();
+        assert!(
+            mempool
+                .insert(request(&node, "a", 2.0), &blockchain)
+                .is_err()
+        );
+        assert!(
+            mempool
+                .insert(request(&node, "a", 1.0), &blockchain)
+                .is_err()
+        );
+        mempool
+            .insert(request(&node, "a", 5.0), &blockchain)
+            .unwrap();
+        assert_eq!(mempool.by_priority().len(), 1);
+        assert_eq!(mempool.get("a").unwrap().fee, 5.0);
+    }
+
+    #[test]
+    fn full_pool_evicts_lowest_fee_and_newest() {
+        let (node, blockchain) = setup();
+        let [a, b, c, d] = [("a", 1.0), ("b", 1.0), ("c", 2.0), ("d", 1.0)]
+            .map(|(id, fee)| request(&node, id, fee));
+        // signatures make sizes differ by a few bytes, there is room for two requests, never three
+        let largest = [&a, &b, &c, &d]
+            .iter()
+            .map(|request| serde_json::to_vec(request).unwrap().len())
+            .max()
+            .unwrap();
+        let mut mempool = Mempool::new(largest * 2);
+        mempool.insert(a, &blockchain).unwrap();
+        mempool.insert(b, &blockchain).unwrap();
+
+        // the newcomer pays more, so the newest of the cheapest goes
+        mempool.insert(c, &blockchain).unwrap();
+        assert_eq!(ids(&mempool), ["c", "a"]);
+
+        // a newcomer paying no more than everyone else does not get in
+        assert!(mempool.insert(d, &blockchain).is_err());
+        assert_eq!(ids(&mempool), ["c", "a"]);
+    }
+
+    #[test]
+    fn forged_fees_are_refused() {
+        let (node, blockchain) = setup();
+        let mut mempool = Mempool::new(usize::MAX);
+        let mut forged = request(&node, "a", 1.0);
+        forged.fee = 2.0;
+        assert!(mempool.insert(forged, &blockchain).is_err());
+
+        let mut unsigned = request(&node, "b", 1.0);
+        unsigned.signature = vec![];
+        assert!(mempool.insert(unsigned, &blockchain).is_err());
+    }
+
+    #[test]
+    fn prune_drops_requests_that_can_no_longer_be_mined() {
+        let (node, mut blockchain) = setup();
+        let mut mempool = Mempool::new(usize::MAX);
+        mempool
+            .insert(request(&node, "a", 50.0), &blockchain)
+            .unwrap();
+        mempool
+            .insert(request(&node, "b", 1.0), &blockchain)
+            .unwrap();
+        let generation = mempool.generation();
+
+        let holders = (0..DEFAULT_REPLICATION_FACTOR)
+            .map(|holder| holder.to_string())
+            .collect();
+        blockchain.stored.insert("a".to_string(), holders);
+        mempool.prune(&blockchain);
+        assert_eq!(ids(&mempool), ["b"]);
+        assert!(mempool.generation() > generation);
+    }
+}
@@ -0,0 +1,89 @@
+use sha2::{Digest, Sha256};
+
+fn hash_pair(left: &str, right: &str) -> String {
+    format!("{:x}", Sha256::digest(format!("{left}{right}").as_bytes()))
+}
+
+fn next_level(level: &[String]) -> Vec<String> {
+    level
+        .chunks(2)
+        .map(|pair| hash_pair(&pair[0], pair.get(1).unwrap_or(&pair[0])))
+        .collect()
+}
+
+/// Merkle root over the chunk hashes of a file, an odd node at any level is paired with itself
+pub fn root(leaves: &[String]) -> String {
+    if leaves.is_empty() {
+        return format!("{:x}", Sha256::digest(b""));
+    }
+
+    let mut level = leaves.to_vec();
+    while level.len() > 1 {
+        level = next_level(&level);
+    }
+
+    level.remove(0)
+}
+
+/// Sibling hashes from the leaf at `index` up to the root
+pub fn proof(leaves: &[String], mut index: usize) -> Vec<String> {
+    let mut siblings = vec![];
+    let mut level = leaves.to_vec();
+    while level.len() > 1 {
+        let sibling = (index ^ 1).min(level.len() - 1);
+        siblings.push(level[sibling].clone());
+        level = next_level(&level);
+        index /= 2;
+    }
+    siblings
+}
+
+pub fn verify(root: &str, leaf: &str, mut index: usize, proof: &[String]) -> bool {
+    let mut hash = leaf.to_string();
+    for sibling in proof {
+        hash = match index % 2 {
+            0 => hash_pair(&hash, sibling),
+            _ => hash_pair(sibling, &hash),
+        };
+        index /= 2;
+    }
+    hash == root
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaves(count: usize) -> Vec<String> {
+        (0..count)
+            .map(|i| format!("{:x}", Sha256::digest(i.to_string().as_bytes())))
+            .collect()
+    }
+
+    #[test]
+    fn every_leaf_proves_against_the_root() {
+        for count in [1, 2, 3, 5] {
+            let leaves = leaves(count);
+            let root = root(&leaves);
+            for (index, leaf) in leaves.iter().enumerate() {
+                assert!(verify(&root, leaf, index, &proof(&leaves, index)));
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_a_wrong_index_or_sibling() {
+        for count in [2, 3, 5] {
+            let leaves = leaves(count);
+            let root = root(&leaves);
+            for (index, leaf) in leaves.iter().enumerate() {
+                let proof = proof(&leaves, index);
+                assert!(!verify(&root, leaf, (index + 1) % count, &proof));
+
+                let mut tampered = proof.clone();
+                tampered[0] = hash_pair(&tampered[0], "");
+                assert!(!verify(&root, leaf, index, &tampered));
+            }
+        }
+    }
+}
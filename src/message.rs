@@ -0,0 +1,160 @@
+use libp2p::gossipsub::MessageAcceptance;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use std::error::Error;
+
+use crate::blockchain::Blockchain;
+use crate::mempool::MemPoolRequest;
+use crate::merkle;
+use crate::network::Channel;
+use crate::transaction::{
+    Ask, Bid, CapacityTx, DealTx, LifecycleTx, ProofOfStorageTx, RetrievalReceipt, TransferTx,
+};
+
+macro_rules! messages {
+    ($($variant:ident($ty:ty)),* $(,)?) => {
+        /// Everything nodes gossip, tagged with its variant so that a payload is never decoded as
+        /// another
+        #[derive(Serialize, Deserialize, Debug, Clone)]
+        pub enum Message {
+            $($variant($ty)),*
+        }
+
+        /// A message about to be sent, borrowed so that nothing is cloned to gossip it. It encodes
+        /// exactly like the `Message` it is received as.
+        #[derive(Serialize, Clone, Copy)]
+        pub enum MessageRef<'a> {
+            $($variant(&'a $ty)),*
+        }
+
+        $(impl<'a> From<&'a $ty> for MessageRef<'a> {
+            fn from(value: &'a $ty) -> Self {
+                MessageRef::$variant(value)
+            }
+        })*
+
+        impl Message {
+            pub fn borrowed(&self) -> MessageRef<'_> {
+                match self {
+                    $(Message::$variant(value) => MessageRef::$variant(value)),*
+                }
+            }
+        }
+    };
+}
+
+messages!(
+    Blockchain(Blockchain),
+    Request(MemPoolRequest),
+    Proof(ProofOfStorageTx),
+    Receipt(RetrievalReceipt),
+    Lifecycle(LifecycleTx),
+    Capacity(CapacityTx),
+    Ask(Ask),
+    Bid(Bid),
+    Deal(DealTx),
+    Transfer(TransferTx),
+);
+
+impl MessageRef<'_> {
+    /// Topic the message is gossiped on
+    pub fn channel(self) -> Channel {
+        match self {
+            MessageRef::Blockchain(_) => Channel::Blocks,
+            MessageRef::Request(_)
+            | MessageRef::Lifecycle(_)
+            | MessageRef::Capacity(_)
+            | MessageRef::Ask(_)
+            | MessageRef::Bid(_)
+            | MessageRef::Deal(_)
+            | MessageRef::Transfer(_) => Channel::Transactions,
+            MessageRef::Proof(_) => Channel::Proofs,
+            MessageRef::Receipt(_) => Channel::Retrieval,
+        }
+    }
+
+    /// JSON as version 1 peers expect it, `{"type": <variant>, "body": <message>}`
+    pub fn to_json(self) -> Result<Vec<u8>, Box<dyn Error>> {
+        let Value::Object(tagged) = serde_json::to_value(self)? else {
+            return Err("message is not tagged".into());
+        };
+        let (kind, body) = tagged.into_iter().next().ok_or("message is not tagged")?;
+        Ok(serde_json::to_vec(&json!({ "type": kind, "body": body }))?)
+    }
+}
+
+impl Message {
+    pub fn channel(&self) -> Channel {
+        self.borrowed().channel()
+    }
+
+    /// Decides whether a message from `sender` is forwarded before it is handled. Forged or
+    /// malformed content is rejected, content that is only stale or of no use anymore is ignored.
+    pub fn validate(&self, sender: &str, blockchain: &Blockchain) -> MessageAcceptance {
+        let verdict = |valid: Result<bool, Box<dyn Error>>, current: bool| match valid {
+            Ok(true) if current => MessageAcceptance::Accept,
+            Ok(true) => MessageAcceptance::Ignore,
+            _ => MessageAcceptance::Reject,
+        };
+
+        match self {
+            Message::Blockchain(received) => {
+                if !received.is_preferred_to(blockchain) {
+                    MessageAcceptance::Ignore
+                } else {
+                    verdict(Ok(received.verify()), true)
+                }
+            }
+            Message::Request(request) => {
+                let valid = request.replicas > 0
+                    && request.fee.is_finite()
+                    && request.fee >= 0.0
+                    && request.verify().unwrap_or(false)
+                    && merkle::root(&request.chunks) == request.merkle_root
+                    && request.acl.as_ref().is_none_or(|acl| {
+                        acl.verify().unwrap_or(false)
+                            && (request.repair.is_some() || acl.owner == request.node_id)
+                    });
+                verdict(Ok(valid), request.check(blockchain).is_ok())
+            }
+            Message::Proof(proof) => {
+                // each holder proves for itself, a holder our chain does not know about yet may
+                // only be ahead of us
+                verdict(
+                    Ok(proof.node_id == sender),
+                    blockchain
+                        .holders(&proof.request_id)
+                        .contains(&proof.node_id),
+                )
+            }
+            Message::Receipt(receipt) => {
+                verdict(receipt.verify(), !blockchain.is_settled(&receipt.nonce))
+            }
+            Message::Lifecycle(ltx) => verdict(ltx.verify(), ltx.check(blockchain).is_ok()),
+            Message::Capacity(ctx) => verdict(ctx.verify(), ctx.check(blockchain).is_ok()),
+            Message::Ask(ask) => verdict(ask.verify(), true),
+            Message::Bid(bid) => verdict(
+                bid.verify(),
+                blockchain.find_storage_tx(&bid.request_id).is_none(),
+            ),
+            Message::Deal(dtx) => verdict(
+                dtx.verify(),
+                !dtx.is_complete() || dtx.check(blockchain).is_ok(),
+            ),
+            // later transfers of an account wait for the earlier ones
+            Message::Transfer(ttx) => {
+                verdict(ttx.verify(), ttx.nonce >= blockchain.next_nonce(&ttx.from))
+            }
+        }
+    }
+
+    pub fn from_json(bytes: &[u8]) -> Result<Self, Box<dyn Error>> {
+        let mut value: Value = serde_json::from_slice(bytes)?;
+        let kind = value["type"]
+            .as_str()
+            .ok_or("message has no type")?
+            .to_string();
+        let body = value["body"].take();
+        Ok(serde_json::from_value(json!({ kind: body }))?)
+    }
+}
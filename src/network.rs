@@ -1,30 +1,175 @@
 use std::{
+    collections::HashMap,
     error::Error,
     hash::{DefaultHasher, Hash, Hasher},
+    time::Instant,
 };
 
 use libp2p::{
-    Swarm,
-    gossipsub::{self, IdentTopic},
-    mdns, noise,
-    swarm::NetworkBehaviour,
+    PeerId, StreamProtocol, Swarm, allow_block_list,
+    gossipsub::{self, IdentTopic, TopicHash, TopicScoreParams},
+    identity, kad, mdns,
+    multiaddr::Protocol,
+    noise,
+    swarm::{NetworkBehaviour, behaviour::toggle::Toggle},
     tcp, yamux,
 };
 use tokio::{io, time::Duration};
 use tracing_subscriber::EnvFilter;
 
+use crate::codec::{JSON_VERSION, PROTOCOL_VERSION};
+use crate::config::Config;
+use crate::message::Message;
+use crate::transfer;
+
+/// Score below which a peer is disconnected and banned, whether gossipsub or the application
+/// brought it there
+pub const BAN_THRESHOLD: f64 = -100.0;
+
 #[derive(NetworkBehaviour)]
 pub struct MyBehaviour {
+    /// Peers banned for misbehaving, their connections are refused
+    pub blocked: allow_block_list::Behaviour<allow_block_list::BlockedPeers>,
     pub gossipsub: gossipsub::Behaviour,
-    pub mdns: mdns::tokio::Behaviour,
+    /// Finds peers beyond the local network, starting from the configured bootstrap nodes
+    pub kad: kad::Behaviour<kad::store::MemoryStore>,
+    /// Finds peers on the local network, off unless the deployment is on a LAN
+    pub mdns: Toggle<mdns::tokio::Behaviour>,
+    pub transfer: transfer::Behaviour,
+}
+
+/// Kademlia protocol of the network, so that our DHT is not mixed with other libp2p networks
+const KAD_PROTOCOL: StreamProtocol = StreamProtocol::new("/pastechain/kad/1");
+
+/// Peers we have not heard from for this long are no longer mirrored to
+const PEER_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// Topic version 1 peers gossip everything on
+const LEGACY_TOPIC: &str = "test-net";
+
+/// Classes of gossip, each on its own topic so that nodes can follow only what they need
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Channel {
+    Blocks,
+    Transactions,
+    Proofs,
+    Retrieval,
+}
+
+impl Channel {
+    pub const ALL: [Channel; 4] = [
+        Channel::Blocks,
+        Channel::Transactions,
+        Channel::Proofs,
+        Channel::Retrieval,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Channel::Blocks => "blocks",
+            Channel::Transactions => "transactions",
+            Channel::Proofs => "proofs",
+            Channel::Retrieval => "retrieval",
+        }
+    }
+
+    pub fn topic(self) -> IdentTopic {
+        IdentTopic::new(format!("pastechain/{}/1", self.name()))
+    }
+
+    /// Largest message accepted on the topic, blocks carry the whole chain
+    pub fn max_size(self) -> usize {
+        match self {
+            Channel::Blocks => 16 << 20,
+            Channel::Transactions => 1 << 20,
+            Channel::Proofs => 256 << 10,
+            Channel::Retrieval => 64 << 10,
+        }
+    }
+
+    /// Invalid blocks and proofs weigh more on a peer's score than junk on the chattier topics.
+    /// Traffic is too sparse to expect a delivery rate from mesh peers.
+    fn score_params(self) -> TopicScoreParams {
+        let (topic_weight, invalid_message_deliveries_weight) = match self {
+            Channel::Blocks => (1.0, -100.0),
+            Channel::Proofs => (0.5, -50.0),
+            Channel::Transactions => (0.5, -10.0),
+            Channel::Retrieval => (0.25, -10.0),
+        };
+        TopicScoreParams {
+            topic_weight,
+            invalid_message_deliveries_weight,
+            first_message_deliveries_cap: 100.0,
+            mesh_message_deliveries_weight: 0.0,
+            mesh_failure_penalty_weight: 0.0,
+            ..Default::default()
+        }
+    }
 }
 
-pub fn setup_p2p_network() -> Result<(Swarm<MyBehaviour>, IdentTopic), Box<dyn Error>> {
+/// Topics we gossip on and the wire version each peer we heard from speaks
+pub struct Gossip {
+    /// Followed by full nodes only, to keep exchanging messages with version 1 peers
+    legacy: Option<IdentTopic>,
+    /// Version and time of the last message of every peer that published something
+    peers: HashMap<PeerId, (u32, Instant)>,
+}
+
+impl Gossip {
+    /// Legacy topic to mirror messages on, as long as version 1 peers are still around
+    pub fn legacy_peers(&self) -> Option<&IdentTopic> {
+        let remain = self
+            .peers
+            .values()
+            .any(|(version, last)| *version == JSON_VERSION && last.elapsed() < PEER_TIMEOUT);
+        self.legacy.as_ref().filter(|_| remain)
+    }
+
+    /// A newer peer's copy for version 1 peers, which we already got on the class topic
+    pub fn is_mirror(&self, topic: &TopicHash, max_version: u32) -> bool {
+        self.legacy
+            .as_ref()
+            .is_some_and(|legacy| legacy.hash() == *topic)
+            && max_version > JSON_VERSION
+    }
+
+    /// Drops messages larger than their topic allows or published on another class's topic
+    pub fn check(
+        &self,
+        topic: &TopicHash,
+        size: usize,
+        message: &Message,
+    ) -> Result<(), Box<dyn Error>> {
+        let channel = message.channel();
+        let legacy = self.legacy.as_ref().map(IdentTopic::hash);
+        if Some(topic) != legacy.as_ref() && *topic != channel.topic().hash() {
+            return Err(format!("{} message on the wrong topic", channel.name()).into());
+        }
+        if size > channel.max_size() {
+            return Err(format!("{} message too large", channel.name()).into());
+        }
+        Ok(())
+    }
+
+    pub fn expired(&mut self, peer_id: &PeerId) {
+        self.peers.remove(peer_id);
+    }
+
+    pub fn heard(&mut self, peer_id: PeerId, max_version: u32) {
+        self.peers
+            .insert(peer_id, (max_version.min(PROTOCOL_VERSION), Instant::now()));
+    }
+}
+
+pub fn setup_p2p_network(
+    config: &Config,
+    keypair: identity::Keypair,
+) -> Result<(Swarm<MyBehaviour>, Gossip), Box<dyn Error>> {
     let _ = tracing_subscriber::fmt()
         .with_env_filter(EnvFilter::from_default_env())
         .try_init();
 
-    let mut swarm = libp2p::SwarmBuilder::with_new_identity()
+    let mut swarm = libp2p::SwarmBuilder::with_existing_identity(keypair)
         .with_tokio()
         .with_tcp(
             tcp::Config::default(),
@@ -42,26 +187,102 @@ pub fn setup_p2p_network() -> Result<(Swarm<MyBehaviour>, IdentTopic), Box<dyn E
             let gossipsub_config = gossipsub::ConfigBuilder::default()
                 .heartbeat_interval(Duration::from_secs(10))
                 .validation_mode(gossipsub::ValidationMode::Strict)
+                .validate_messages()
                 .message_id_fn(message_id_fn)
+                .max_transmit_size(Channel::Blocks.max_size())
                 .build()
-                .map_err(|msg| io::Error::new(io::ErrorKind::Other, msg))?;
+                .map_err(io::Error::other)?;
 
-            let gossipsub = gossipsub::Behaviour::new(
+            let mut gossipsub = gossipsub::Behaviour::new(
                 gossipsub::MessageAuthenticity::Signed(key.clone()),
                 gossipsub_config,
             )?;
+            let score_params = gossipsub::PeerScoreParams {
+                topics: Channel::ALL
+                    .into_iter()
+                    .map(|channel| (channel.topic().hash(), channel.score_params()))
+                    .collect(),
+                // application penalties count as they are
+                app_specific_weight: 1.0,
+                ..Default::default()
+            };
+            let thresholds = gossipsub::PeerScoreThresholds {
+                gossip_threshold: -20.0,
+                publish_threshold: -50.0,
+                graylist_threshold: -80.0,
+                ..Default::default()
+            };
+            gossipsub
+                .with_peer_score(score_params, thresholds)
+                .map_err(io::Error::other)?;
+
+            let peer_id = key.public().to_peer_id();
+            let mut kad_config = kad::Config::new(KAD_PROTOCOL);
+            // providers that go away stop being found within the hour
+            kad_config
+                .set_provider_publication_interval(Some(Duration::from_secs(10 * 60)))
+                .set_provider_record_ttl(Some(Duration::from_secs(60 * 60)));
+            let mut kad = kad::Behaviour::with_config(
+                peer_id,
+                kad::store::MemoryStore::new(peer_id),
+                kad_config,
+            );
+            // answer DHT queries even before we know our external address
+            kad.set_mode(Some(kad::Mode::Server));
 
-            let mdns =
-                mdns::tokio::Behaviour::new(mdns::Config::default(), key.public().to_peer_id())?;
-            Ok(MyBehaviour { gossipsub, mdns })
+            let mdns = config
+                .mdns
+                .then(|| mdns::tokio::Behaviour::new(mdns::Config::default(), peer_id))
+                .transpose()?;
+            Ok(MyBehaviour {
+                blocked: allow_block_list::Behaviour::default(),
+                gossipsub,
+                kad,
+                mdns: mdns.into(),
+                transfer: transfer::new_behaviour(),
+            })
         })?
         .build();
 
-    let topic = gossipsub::IdentTopic::new("test-net");
-    swarm.behaviour_mut().gossipsub.subscribe(&topic)?;
+    // version 1 peers gossip everything on one topic, light nodes leave them to full nodes
+    let legacy = (config.channels.len() == Channel::ALL.len())
+        .then(|| gossipsub::IdentTopic::new(LEGACY_TOPIC));
+    if let Some(legacy) = &legacy {
+        swarm.behaviour_mut().gossipsub.subscribe(legacy)?;
+    }
+    for channel in &config.channels {
+        swarm
+            .behaviour_mut()
+            .gossipsub
+            .subscribe(&channel.topic())?;
+    }
+
+    for address in &config.listen {
+        swarm.listen_on(address.clone())?;
+    }
+    println!("[#] Peer id {}", swarm.local_peer_id());
 
-    swarm.listen_on("/ip4/0.0.0.0/udp/0/quic-v1".parse()?)?;
-    swarm.listen_on("/ip4/0.0.0.0/tcp/0".parse()?)?;
+    for address in &config.bootstrap {
+        if let Some(Protocol::P2p(peer_id)) = address.iter().last() {
+            swarm
+                .behaviour_mut()
+                .kad
+                .add_address(&peer_id, address.clone());
+        }
+        swarm
+            .dial(address.clone())
+            .inspect_err(|e| println!("[!!] Cannot dial bootstrap node {address}: {e}"))
+            .ok();
+    }
+    if !config.bootstrap.is_empty() {
+        swarm.behaviour_mut().kad.bootstrap().ok();
+    }
 
-    Ok((swarm, topic))
+    Ok((
+        swarm,
+        Gossip {
+            legacy,
+            peers: HashMap::new(),
+        },
+    ))
 }
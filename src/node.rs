@@ -1,6 +1,7 @@
 use identity::Keypair;
 use libp2p::PeerId;
 use libp2p::identity;
+use std::error::Error;
 
 #[derive(Debug, Clone)]
 pub struct Node {
@@ -10,15 +11,9 @@ pub struct Node {
 }
 
 impl Node {
-    /// Creates a new NodeInfo instance with randomly generated Ed25519 keypair
-    ///
-    /// # Returns
-    ///
-    /// * `Result<NodeInfo, Box<dyn Error>>` - A Result containing either:
-    ///   * `Ok(NodeInfo)` - A new NodeInfo instance with generated ID and keys
-    ///   * `Err` - If there was an error encoding the private key
-    pub fn new() -> Self {
-        let keypair = identity::Keypair::generate_ed25519();
+    /// Creates the node from its keypair, the same one that is its libp2p identity, so that its
+    /// id is also its `PeerId`
+    pub fn new(keypair: Keypair) -> Self {
         let id = PeerId::from(keypair.public()).to_string();
         let public_key = keypair.public().encode_protobuf(); // Extract public key
         let private_key = keypair; // The keypair itself acts as the private key
@@ -30,3 +25,18 @@ impl Node {
         }
     }
 }
+
+/// Checks that `signature` over `message` was made by the node `id`, whose key must be the one
+/// given
+pub fn verify_signature(
+    id: &str,
+    public_key: &[u8],
+    message: &[u8],
+    signature: &[u8],
+) -> Result<bool, Box<dyn Error>> {
+    let public_key = identity::PublicKey::try_decode_protobuf(public_key)?;
+    if PeerId::from_public_key(&public_key).to_string() != id {
+        return Ok(false);
+    }
+    Ok(public_key.verify(message, signature))
+}
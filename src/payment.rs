@@ -0,0 +1,60 @@
+use std::error::Error;
+use uuid::Uuid;
+
+use crate::blockchain::RETRIEVAL_FEE_PER_CHUNK;
+use crate::node::{Node, verify_signature};
+use crate::transaction::{MonetaryTx, RetrievalReceipt};
+
+impl RetrievalReceipt {
+    fn signing_bytes(&self) -> Vec<u8> {
+        serde_json::to_vec(&(
+            &self.request_id,
+            &self.payer,
+            &self.payee,
+            &self.chunk_hashes,
+            self.amount,
+            &self.nonce,
+        ))
+        .unwrap_or_default()
+    }
+
+    /// Pays `payee` the fixed fee for every chunk it delivered and we verified
+    pub fn new(
+        node: &Node,
+        request_id: &str,
+        payee: &str,
+        chunk_hashes: Vec<String>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let mut receipt = RetrievalReceipt {
+            request_id: request_id.to_string(),
+            payer: node.id.clone(),
+            payee: payee.to_string(),
+            amount: chunk_hashes.len() as f64 * RETRIEVAL_FEE_PER_CHUNK,
+            chunk_hashes,
+            nonce: Uuid::new_v4().to_string(),
+            signature: vec![],
+            public_key: node.public_key.clone(),
+        };
+        receipt.signature = node.private_key.sign(&receipt.signing_bytes())?;
+
+        Ok(receipt)
+    }
+
+    pub fn verify(&self) -> Result<bool, Box<dyn Error>> {
+        verify_signature(
+            &self.payer,
+            &self.public_key,
+            &self.signing_bytes(),
+            &self.signature,
+        )
+    }
+
+    /// On-chain transaction moving the fee to the payee
+    pub fn settlement(&self) -> MonetaryTx {
+        MonetaryTx {
+            node_id: self.payee.clone(),
+            amount: self.amount,
+            receipt: Some(self.clone()),
+        }
+    }
+}
@@ -1,15 +1,22 @@
 use rand::Rng;
-use sha2::{Digest, Sha256};
+use sha2::Digest;
+
+const CHALLENGE_LEN: usize = 100;
 
 pub fn generate_new_challenge(file_size: usize) -> (usize, usize) {
+    // small files and shards are challenged as a whole
+    if file_size <= CHALLENGE_LEN {
+        return (0, file_size);
+    }
+
     let mut rng = rand::rng();
-    let start = rng.random_range(0..file_size - 100);
-    let end = start + 100;
+    let start = rng.random_range(0..file_size - CHALLENGE_LEN);
+    let end = start + CHALLENGE_LEN;
 
     (start, end)
 }
 
-pub fn validate(file: &Vec<u8>, start: usize, end: usize, hash: &str) -> bool {
+pub fn validate(file: &[u8], start: usize, end: usize, hash: &str) -> bool {
     // Check if range is valid
     if start >= file.len() || end > file.len() || start >= end {
         return false;
@@ -0,0 +1,41 @@
+use libp2p::kad::{self, QueryId, RecordKey, store::MemoryStore};
+use std::collections::{HashMap, HashSet};
+
+/// Provider records announcing the files this node holds in the DHT, and lookups of other
+/// holders, so retrieval does not depend on the on-chain assignments alone
+#[derive(Default)]
+pub struct Providers {
+    providing: HashSet<String>,
+    lookups: HashMap<QueryId, String>,
+}
+
+impl Providers {
+    /// Announces the files we started holding and withdraws the ones we dropped. Kademlia
+    /// republishes the records it keeps announcing on its own.
+    pub fn refresh(&mut self, kad: &mut kad::Behaviour<MemoryStore>, held: HashSet<String>) {
+        for request_id in held.difference(&self.providing) {
+            kad.start_providing(RecordKey::new(request_id))
+                .inspect_err(|e| println!("[!!] Cannot announce {request_id}: {e}"))
+                .ok();
+        }
+        for request_id in self.providing.difference(&held) {
+            kad.stop_providing(&RecordKey::new(request_id));
+        }
+        self.providing = held;
+    }
+
+    /// Starts looking for the holders of a file
+    pub fn find(&mut self, kad: &mut kad::Behaviour<MemoryStore>, request_id: &str) {
+        let id = kad.get_providers(RecordKey::new(&request_id));
+        self.lookups.insert(id, request_id.to_string());
+    }
+
+    /// File a lookup is for, forgetting the lookup once it made its last step
+    pub fn lookup(&mut self, id: QueryId, last: bool) -> Option<String> {
+        if last {
+            self.lookups.remove(&id)
+        } else {
+            self.lookups.get(&id).cloned()
+        }
+    }
+}
@@ -16,5 +16,13 @@ pub fn is_elected(id: &str, block_hash: &str, total_nodes: u64) -> bool {
 
     let rn = get_deterministic_random(&seed, 0, total_nodes);
 
-    rn <= (total_nodes + M - 1) / M
+    rn <= total_nodes.div_ceil(M)
+}
+
+/// Like `is_elected`, but a miner also has to pass a second draw with probability `room`, so that
+/// miners with more free capacity store more of the new files and no one miner takes them all
+pub fn is_elected_with_room(id: &str, block_hash: &str, total_nodes: u64, room: f64) -> bool {
+    let draw = get_deterministic_random(&format!("{id}{block_hash}room"), 0, 1000) as f64 / 1000.0;
+
+    is_elected(id, block_hash, total_nodes) && draw < room
 }
@@ -0,0 +1,137 @@
+use std::collections::HashMap;
+use std::error::Error;
+
+use crate::blockchain::Blockchain;
+use crate::node::{Node, verify_signature};
+use crate::transaction::RepairTx;
+
+/// Validation epochs a holder may go without a verified proof before its replica counts as lost
+const MAX_MISSED_EPOCHS: u64 = 3;
+
+/// Tracks which holders keep proving their replicas and finds files that fell below k copies
+pub struct ReplicaMonitor {
+    epoch: u64,
+    last_proof: HashMap<String, HashMap<String, u64>>,
+    repairing: HashMap<String, u64>,
+}
+
+impl ReplicaMonitor {
+    pub fn new() -> Self {
+        Self {
+            epoch: 0,
+            last_proof: HashMap::new(),
+            repairing: HashMap::new(),
+        }
+    }
+
+    pub fn record_proof(&mut self, request_id: &str, node_id: &str) {
+        self.last_proof
+            .entry(request_id.to_string())
+            .or_default()
+            .insert(node_id.to_string(), self.epoch);
+    }
+
+    /// Whether `holder_id` went more than `MAX_MISSED_EPOCHS` without a proof we saw. Holders that
+    /// never proved anything count from the first proof we saw for the file.
+    pub fn has_missed(&self, request_id: &str, holder_id: &str) -> bool {
+        let Some(seen) = self.last_proof.get(request_id) else {
+            return false;
+        };
+        let last = seen
+            .get(holder_id)
+            .or_else(|| seen.values().min())
+            .copied()
+            .unwrap_or(self.epoch);
+        self.epoch - last > MAX_MISSED_EPOCHS
+    }
+
+    /// Advances one epoch and returns `(request_id, stale holders)` for every file this node holds
+    /// that has fewer than k healthy replicas and no repair already in flight
+    pub fn next_epoch(
+        &mut self,
+        blockchain: &Blockchain,
+        node_id: &str,
+    ) -> Vec<(String, Vec<String>)> {
+        self.epoch += 1;
+        let epoch = self.epoch;
+        let mut repairs = vec![];
+
+        // files whose deal ended are no longer watched
+        self.last_proof
+            .retain(|request_id, _| blockchain.stored.contains_key(request_id));
+
+        for (request_id, holders) in &blockchain.stored {
+            if !holders.contains(&node_id.to_string()) {
+                continue;
+            }
+
+            // holders we have not heard from yet get a full grace period starting now
+            let seen = self.last_proof.entry(request_id.clone()).or_default();
+            seen.insert(node_id.to_string(), epoch);
+            let stale: Vec<String> = holders
+                .iter()
+                .filter(|holder| {
+                    let last = *seen.entry(holder.to_string()).or_insert(epoch);
+                    epoch - last > MAX_MISSED_EPOCHS
+                })
+                .cloned()
+                .collect();
+
+            if holders.len() - stale.len() >= blockchain.replicas(request_id) {
+                continue;
+            }
+            if let Some(since) = self.repairing.get(request_id)
+                && epoch - since <= MAX_MISSED_EPOCHS
+            {
+                continue;
+            }
+
+            self.repairing.insert(request_id.clone(), epoch);
+            repairs.push((request_id.clone(), stale));
+        }
+
+        repairs
+    }
+}
+
+impl RepairTx {
+    fn signing_bytes(request_id: &str, holder_id: &str, dropped: &[String]) -> Vec<u8> {
+        serde_json::to_vec(&(request_id, holder_id, dropped)).unwrap_or_default()
+    }
+
+    pub fn new(
+        node: &Node,
+        request_id: &str,
+        dropped: Vec<String>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let signature = node
+            .private_key
+            .sign(&Self::signing_bytes(request_id, &node.id, &dropped))?;
+
+        Ok(RepairTx {
+            request_id: request_id.to_string(),
+            holder_id: node.id.clone(),
+            dropped,
+            signature,
+            public_key: node.public_key.clone(),
+        })
+    }
+
+    /// Checks the signature, that it comes from a current holder of the file and that the holder
+    /// only drops other holders, never enough to leave the file without a replica
+    pub fn verify(&self, blockchain: &Blockchain) -> Result<bool, Box<dyn Error>> {
+        let holders = blockchain.holders(&self.request_id);
+        if !holders.contains(&self.holder_id)
+            || self.dropped.contains(&self.holder_id)
+            || self.dropped.iter().any(|id| !holders.contains(id))
+        {
+            return Ok(false);
+        }
+        verify_signature(
+            &self.holder_id,
+            &self.public_key,
+            &Self::signing_bytes(&self.request_id, &self.holder_id, &self.dropped),
+            &self.signature,
+        )
+    }
+}
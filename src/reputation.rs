@@ -0,0 +1,150 @@
+use libp2p::{PeerId, Swarm};
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+use crate::network::{BAN_THRESHOLD, MyBehaviour};
+
+/// How long a banned peer is kept out
+const BAN_DURATION: Duration = Duration::from_secs(30 * 60);
+
+/// Share of its penalties a peer keeps at every decay, so honest peers recover from mistakes
+const DECAY: f64 = 0.9;
+
+/// Gossip messages a peer may publish between two decays before it counts as spamming, on top of
+/// one proof for every file it holds
+const MAX_MESSAGES: usize = 500;
+
+/// Misbehaviour punished on top of what gossipsub scores on its own
+#[derive(Clone, Copy, Debug)]
+pub enum Offence {
+    InvalidMessage,
+    InvalidBlock,
+    BadProof,
+    Spam,
+    FailedToServe,
+}
+
+impl Offence {
+    fn penalty(self) -> f64 {
+        match self {
+            Offence::InvalidMessage => 10.0,
+            Offence::InvalidBlock => 50.0,
+            Offence::BadProof => 25.0,
+            Offence::Spam => 20.0,
+            Offence::FailedToServe => 5.0,
+        }
+    }
+}
+
+/// Application score of every peer, fed into gossipsub, and the peers banned for misbehaving
+#[derive(Default)]
+pub struct Reputation {
+    scores: HashMap<PeerId, f64>,
+    messages: HashMap<PeerId, usize>,
+    /// Peers already penalized for spamming since the last decay
+    spamming: HashSet<PeerId>,
+    banned: HashMap<PeerId, Instant>,
+}
+
+impl Reputation {
+    pub fn is_banned(&self, peer: &PeerId) -> bool {
+        self.banned.contains_key(peer)
+    }
+
+    /// Lowers the score of `peer`, banning it once the score falls below the threshold
+    pub fn penalize(&mut self, swarm: &mut Swarm<MyBehaviour>, peer: PeerId, offence: Offence) {
+        let score = self.scores.entry(peer).or_insert(0.0);
+        *score -= offence.penalty();
+        let score = *score;
+        println!("[!!] Peer {peer} penalized for {offence:?}, score is now {score:.1}");
+
+        swarm
+            .behaviour_mut()
+            .gossipsub
+            .set_application_score(&peer, score);
+        if score < BAN_THRESHOLD {
+            self.ban(swarm, peer);
+        }
+    }
+
+    /// Counts a gossip message `peer` published, penalizing it once per decay for going over its
+    /// allowance. Relays are not counted, they only forward what others publish.
+    pub fn heard(&mut self, swarm: &mut Swarm<MyBehaviour>, peer: PeerId, files_held: usize) {
+        let count = self.messages.entry(peer).or_insert(0);
+        *count += 1;
+        if *count > MAX_MESSAGES + files_held && self.spamming.insert(peer) {
+            self.penalize(swarm, peer, Offence::Spam);
+        }
+    }
+
+    /// Disconnects `peer` and refuses it until the ban expires
+    pub fn ban(&mut self, swarm: &mut Swarm<MyBehaviour>, peer: PeerId) {
+        if self.banned.contains_key(&peer) {
+            return;
+        }
+        self.banned.insert(peer, Instant::now() + BAN_DURATION);
+        swarm.behaviour_mut().gossipsub.blacklist_peer(&peer);
+        swarm.behaviour_mut().blocked.block_peer(peer);
+        println!(
+            "[!!] Banned peer {peer} for {} minutes",
+            BAN_DURATION.as_secs() / 60
+        );
+    }
+
+    /// Forgives part of every penalty, bans peers gossipsub scored below the threshold and lifts
+    /// expired bans
+    pub fn decay(&mut self, swarm: &mut Swarm<MyBehaviour>) {
+        self.messages.clear();
+        self.spamming.clear();
+        for (peer, score) in self.scores.iter_mut() {
+            *score *= DECAY;
+            if *score > -1.0 {
+                *score = 0.0;
+            }
+            swarm
+                .behaviour_mut()
+                .gossipsub
+                .set_application_score(peer, *score);
+        }
+        self.scores.retain(|_, score| *score < 0.0);
+
+        let low: Vec<PeerId> = swarm
+            .behaviour()
+            .gossipsub
+            .all_peers()
+            .map(|(peer, _)| *peer)
+            .filter(|peer| {
+                swarm
+                    .behaviour()
+                    .gossipsub
+                    .peer_score(peer)
+                    .is_some_and(|score| score < BAN_THRESHOLD)
+            })
+            .collect();
+        for peer in low {
+            self.ban(swarm, peer);
+        }
+
+        let now = Instant::now();
+        let expired: Vec<PeerId> = self
+            .banned
+            .iter()
+            .filter(|(_, until)| **until <= now)
+            .map(|(peer, _)| *peer)
+            .collect();
+        for peer in expired {
+            self.banned.remove(&peer);
+            self.scores.remove(&peer);
+            swarm
+                .behaviour_mut()
+                .gossipsub
+                .remove_blacklisted_peer(&peer);
+            swarm.behaviour_mut().blocked.unblock_peer(peer);
+            swarm
+                .behaviour_mut()
+                .gossipsub
+                .set_application_score(&peer, 0.0);
+            println!("[#] Ban of peer {peer} expired");
+        }
+    }
+}
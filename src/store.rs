@@ -0,0 +1,196 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::block::Block;
+use crate::blockchain::Blockchain;
+use crate::mempool::{MemPoolRequest, Mempool};
+
+/// One block per line, only ever appended to unless the node switches to another fork
+const BLOCK_LOG: &str = "blocks.log";
+const STATE_FILE: &str = "state.json";
+const PENDING_FILE: &str = "pending.json";
+
+/// State derived from the chain, plus the balances which also carry local proof rewards
+#[derive(Serialize, Deserialize)]
+struct State {
+    tip: String,
+    height: usize,
+    stored: HashMap<String, Vec<String>>,
+    balance: HashMap<String, f64>,
+    deals: HashMap<String, u64>,
+    escrow: HashMap<String, f64>,
+}
+
+/// Requests waiting to be mined and the chunk lists of our own uploads that miners fetch from us
+#[derive(Serialize, Deserialize, Default)]
+pub struct Pending {
+    pub mempool: Vec<MemPoolRequest>,
+    pub uploads: HashMap<String, Vec<String>>,
+}
+
+/// Chain, derived state and mempool of this node, kept across restarts
+pub struct Store {
+    dir: PathBuf,
+    /// Number of blocks in the log and hash of the last one
+    persisted: usize,
+    tip: Option<String>,
+    /// Chain generation whose state was last written, `None` before the first write
+    last_state: Option<u64>,
+    /// Mempool generation and uploads last written, `None` before the first write
+    last_pending: Option<(u64, HashMap<String, Vec<String>>)>,
+}
+
+/// Replaces `path` in one step, so a crash leaves either the old or the new file
+fn write_atomic(path: &Path, bytes: &[u8]) -> Result<(), Box<dyn Error>> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, bytes)?;
+    fs::rename(tmp, path)?;
+    Ok(())
+}
+
+impl Store {
+    /// Reloads the chain by replaying every logged block, so a corrupt or tampered log is cut at
+    /// the first block that does not verify
+    pub fn open(dir: PathBuf) -> Result<(Self, Blockchain, Pending), Box<dyn Error>> {
+        fs::create_dir_all(&dir)?;
+
+        let mut blockchain = Blockchain::new_with_genesis_block();
+        let log = fs::read_to_string(dir.join(BLOCK_LOG)).unwrap_or_default();
+        let mut blocks = log.lines().map(serde_json::from_str::<Block>);
+        let genesis_ok = match blocks.next() {
+            Some(Ok(genesis)) => Some(&genesis.hash) == blockchain.chain.first().map(|b| &b.hash),
+            Some(Err(_)) => false,
+            None => true,
+        };
+        if genesis_ok {
+            for block in blocks {
+                let Ok(block) = block else { break };
+                if let Err(e) = blockchain.verify_block(&block) {
+                    println!(
+                        "[!!] Stored chain is invalid from block {}: {e}",
+                        block.hash
+                    );
+                    break;
+                }
+                blockchain.add_block(block);
+            }
+        } else {
+            println!("[!!] Stored chain has a different genesis, starting over");
+        }
+
+        // blocks are checked without balances, so the replay does not depend on them. Local proof
+        // rewards only live in the snapshot's balances, which are only trusted when the rest of it
+        // agrees with the replayed chain.
+        let state = fs::read(dir.join(STATE_FILE))
+            .ok()
+            .and_then(|bytes| serde_json::from_slice::<State>(&bytes).ok());
+        if let Some(state) = state {
+            if Some(&state.tip) == blockchain.chain.last().map(|b| &b.hash)
+                && state.height == blockchain.chain.len()
+                && state.stored == blockchain.stored
+                && state.deals == blockchain.deals
+            {
+                blockchain.balance = state.balance;
+            } else {
+                println!("[!!] Stored state does not match the chain, rebuilt it from the blocks");
+            }
+        }
+
+        let pending = fs::read(dir.join(PENDING_FILE))
+            .ok()
+            .and_then(|bytes| serde_json::from_slice::<Pending>(&bytes).ok())
+            .unwrap_or_default();
+
+        println!(
+            "[#] Loaded {} blocks and {} pending requests",
+            blockchain.chain.len(),
+            pending.mempool.len()
+        );
+
+        let mut store = Store {
+            dir,
+            persisted: 0,
+            tip: None,
+            last_state: None,
+            last_pending: None,
+        };
+        // rewrite the log so that anything cut off above is gone
+        store.save_chain(&blockchain)?;
+
+        Ok((store, blockchain, pending))
+    }
+
+    fn save_chain(&mut self, blockchain: &Blockchain) -> Result<(), Box<dyn Error>> {
+        let path = self.dir.join(BLOCK_LOG);
+        let extends = self.persisted > 0
+            && self.persisted <= blockchain.chain.len()
+            && blockchain.chain.get(self.persisted - 1).map(|b| &b.hash) == self.tip.as_ref();
+
+        if extends {
+            let mut log = OpenOptions::new().append(true).open(&path)?;
+            for block in &blockchain.chain[self.persisted..] {
+                writeln!(log, "{}", serde_json::to_string(block)?)?;
+            }
+        } else {
+            // we switched to another fork, so the whole log is replaced
+            let mut log = vec![];
+            for block in &blockchain.chain {
+                writeln!(log, "{}", serde_json::to_string(block)?)?;
+            }
+            write_atomic(&path, &log)?;
+        }
+
+        self.persisted = blockchain.chain.len();
+        self.tip = blockchain.chain.last().map(|b| b.hash.clone());
+        Ok(())
+    }
+
+    /// Writes whatever changed since the last call
+    pub fn save(
+        &mut self,
+        blockchain: &Blockchain,
+        mempool: &Mempool,
+        uploads: &HashMap<String, Vec<String>>,
+    ) -> Result<(), Box<dyn Error>> {
+        if self.persisted != blockchain.chain.len()
+            || self.tip.as_ref() != blockchain.chain.last().map(|b| &b.hash)
+        {
+            self.save_chain(blockchain)?;
+        }
+
+        // the state and the mempool can be large, so they are only serialized when they changed
+        if self.last_state != Some(blockchain.generation()) {
+            let state = serde_json::to_vec(&State {
+                tip: self.tip.clone().unwrap_or_default(),
+                height: self.persisted,
+                stored: blockchain.stored.clone(),
+                balance: blockchain.balance.clone(),
+                deals: blockchain.deals.clone(),
+                escrow: blockchain.escrow.clone(),
+            })?;
+            write_atomic(&self.dir.join(STATE_FILE), &state)?;
+            self.last_state = Some(blockchain.generation());
+        }
+
+        let changed = self
+            .last_pending
+            .as_ref()
+            .is_none_or(|(generation, saved)| {
+                *generation != mempool.generation() || saved != uploads
+            });
+        if changed {
+            let pending = serde_json::to_vec(&Pending {
+                mempool: mempool.by_priority().into_iter().cloned().collect(),
+                uploads: uploads.clone(),
+            })?;
+            write_atomic(&self.dir.join(PENDING_FILE), &pending)?;
+            self.last_pending = Some((mempool.generation(), uploads.clone()));
+        }
+
+        Ok(())
+    }
+}
@@ -6,6 +6,52 @@ pub struct StorageTx {
     pub request_id: String,
     pub file_hash: String,
     pub file_size: usize,
+    pub merkle_root: String,
+    pub replicas: usize,
+    pub chunks: Vec<String>,
+    #[serde(default)]
+    pub replaces: Vec<String>,
+    /// Signed by the holder that asked for the repair, required whenever `replaces` is not empty
+    #[serde(default)]
+    pub repair: Option<RepairTx>,
+    #[serde(default)]
+    pub shard: Option<ShardInfo>,
+    #[serde(default)]
+    pub acl: Option<Acl>,
+    /// Uploader of the file, the only node that may renew or delete it
+    #[serde(default)]
+    pub owner: String,
+    /// Epoch at which the storage deal ends, `None` for files stored before deals had an end
+    #[serde(default)]
+    pub end_epoch: Option<u64>,
+    /// Fee the owner pays the miner of this replica, with the owner's signature committing to it
+    #[serde(default)]
+    pub fee: f64,
+    #[serde(default)]
+    pub fee_signature: Vec<u8>,
+    #[serde(default)]
+    pub fee_public_key: Vec<u8>,
+}
+
+/// Owner-signed list of the peers allowed to retrieve a file, files without one are public
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Acl {
+    pub request_id: String,
+    pub owner: String,
+    pub readers: Vec<String>,
+    pub signature: Vec<u8>,
+    pub public_key: Vec<u8>,
+}
+
+/// Position of an erasure-coded shard within its original file
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ShardInfo {
+    pub file_id: String,
+    pub index: usize,
+    pub data_shards: usize,
+    pub parity_shards: usize,
+    pub file_size: usize,
+    pub file_hash: String,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -21,15 +67,116 @@ pub struct ProofOfStorageTx {
 pub struct MonetaryTx {
     pub node_id: String,
     pub amount: f64,
+    #[serde(default)]
+    pub receipt: Option<RetrievalReceipt>,
+}
+
+/// Coins moved between accounts, wallet addresses or node ids, signed by the sender
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TransferTx {
+    pub from: String,
+    pub to: String,
+    pub amount: f64,
+    /// The sender's transfers are numbered from 1, so none can be replayed or reordered
+    pub nonce: u64,
+    pub signature: Vec<u8>,
+    pub public_key: Vec<u8>,
+}
+
+/// Signed by the retrieving node once it has verified the chunks a holder served, settling the
+/// retrieval fee from `payer` to `payee` when mined
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RetrievalReceipt {
+    pub request_id: String,
+    pub payer: String,
+    pub payee: String,
+    pub chunk_hashes: Vec<String>,
+    pub amount: f64,
+    pub nonce: String,
+    pub signature: Vec<u8>,
+    pub public_key: Vec<u8>,
+}
+
+/// Storage a miner offers to the network and the price it asks, the latest `sequence` counts
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CapacityTx {
+    pub miner_id: String,
+    /// Bytes of file data the miner is willing to hold
+    pub capacity: u64,
+    /// Asked per MiB and epoch
+    pub price: f64,
+    pub sequence: u64,
+    pub signature: Vec<u8>,
+    pub public_key: Vec<u8>,
+}
+
+/// Standing offer of a miner to store files within a size range for up to `duration` epochs
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Ask {
+    pub miner_id: String,
+    /// Per MiB and epoch
+    pub price: f64,
+    pub min_size: u64,
+    pub max_size: u64,
+    pub duration: u64,
+    pub signature: Vec<u8>,
+    pub public_key: Vec<u8>,
 }
 
+/// Call of a client for `replicas` miners to store a file, paying at most `max_price`
 #[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct QueryTx {
+pub struct Bid {
+    pub client_id: String,
     pub request_id: String,
+    pub file_size: u64,
+    pub replicas: usize,
+    pub duration: u64,
+    /// Per MiB and epoch
+    pub max_price: f64,
+    pub signature: Vec<u8>,
+    pub public_key: Vec<u8>,
+}
+
+/// Storage deal between a client and a miner, recorded on-chain with both signatures before
+/// any data is sent. Recording it moves the full price from the client into escrow.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DealTx {
+    pub request_id: String,
+    pub client_id: String,
+    pub miner_id: String,
+    pub file_size: u64,
+    pub duration: u64,
+    /// Per MiB and epoch
+    pub price: f64,
+    pub client_signature: Vec<u8>,
+    pub client_public_key: Vec<u8>,
+    pub miner_signature: Vec<u8>,
+    pub miner_public_key: Vec<u8>,
+}
+
+/// Owner-signed change to the storage deal of a file
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LifecycleTx {
+    pub request_id: String,
+    pub owner: String,
+    pub action: LifecycleAction,
+    pub signature: Vec<u8>,
+    pub public_key: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum LifecycleAction {
+    /// Moves the end of the deal to `end_epoch`, locking `escrow` from the owner's balance
+    Renew { end_epoch: u64, escrow: f64 },
+    /// Ends the deal right away
+    Delete,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct ServeFileTx {
+pub struct RepairTx {
     pub request_id: String,
-    pub file_content: Vec<u8>,
+    pub holder_id: String,
+    pub dropped: Vec<String>,
+    pub signature: Vec<u8>,
+    pub public_key: Vec<u8>,
 }
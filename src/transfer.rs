@@ -0,0 +1,132 @@
+use async_trait::async_trait;
+use futures::prelude::*;
+use libp2p::{
+    StreamProtocol,
+    request_response::{self, ProtocolSupport},
+};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use std::io;
+
+use crate::codec;
+
+/// Point-to-point protocol through which miners pull the chunks of a request from its uploader and
+/// retrieving nodes pull them from the holders. Version 2 speaks the binary codec and is preferred,
+/// version 1 JSON is kept for older peers.
+pub const CHUNK_PROTOCOL: StreamProtocol = StreamProtocol::new("/pastechain/chunk/2");
+pub const CHUNK_PROTOCOL_JSON: StreamProtocol = StreamProtocol::new("/pastechain/chunk/1");
+
+const REQUEST_SIZE_MAXIMUM: u64 = 1024 * 1024;
+const RESPONSE_SIZE_MAXIMUM: u64 = 10 * 1024 * 1024;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChunkRequest {
+    pub request_id: String,
+    pub index: usize,
+    pub chunk_hash: String,
+    /// Fee per chunk the requester commits to pay a holder, miners fetching from an uploader pay nothing
+    #[serde(default)]
+    pub fee: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChunkResponse {
+    pub request_id: String,
+    pub index: usize,
+    pub chunk_hash: String,
+    /// `None` when the peer does not have the chunk or may not serve it
+    pub chunk: Option<Vec<u8>>,
+    /// Merkle path from the chunk up to the root committed in the storage tx
+    pub proof: Vec<String>,
+}
+
+/// Encodes chunk requests and responses as the negotiated protocol version expects
+#[derive(Default, Clone)]
+pub struct Codec;
+
+fn encode<T: Serialize>(protocol: &StreamProtocol, value: &T) -> io::Result<Vec<u8>> {
+    if *protocol == CHUNK_PROTOCOL_JSON {
+        Ok(serde_json::to_vec(value)?)
+    } else {
+        codec::to_bytes(value).map_err(|e| io::Error::other(e.to_string()))
+    }
+}
+
+async fn decode<T, R>(protocol: &StreamProtocol, io: &mut R, limit: u64) -> io::Result<T>
+where
+    T: DeserializeOwned,
+    R: AsyncRead + Unpin + Send,
+{
+    let mut bytes = Vec::new();
+    io.take(limit).read_to_end(&mut bytes).await?;
+
+    if *protocol == CHUNK_PROTOCOL_JSON {
+        Ok(serde_json::from_slice(&bytes)?)
+    } else {
+        codec::from_bytes(&bytes).map_err(|e| io::Error::other(e.to_string()))
+    }
+}
+
+#[async_trait]
+impl request_response::Codec for Codec {
+    type Protocol = StreamProtocol;
+    type Request = ChunkRequest;
+    type Response = ChunkResponse;
+
+    async fn read_request<T>(
+        &mut self,
+        protocol: &StreamProtocol,
+        io: &mut T,
+    ) -> io::Result<ChunkRequest>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        decode(protocol, io, REQUEST_SIZE_MAXIMUM).await
+    }
+
+    async fn read_response<T>(
+        &mut self,
+        protocol: &StreamProtocol,
+        io: &mut T,
+    ) -> io::Result<ChunkResponse>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        decode(protocol, io, RESPONSE_SIZE_MAXIMUM).await
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        protocol: &StreamProtocol,
+        io: &mut T,
+        request: ChunkRequest,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        io.write_all(&encode(protocol, &request)?).await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        protocol: &StreamProtocol,
+        io: &mut T,
+        response: ChunkResponse,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        io.write_all(&encode(protocol, &response)?).await
+    }
+}
+
+pub type Behaviour = request_response::Behaviour<Codec>;
+
+pub fn new_behaviour() -> Behaviour {
+    request_response::Behaviour::new(
+        [
+            (CHUNK_PROTOCOL, ProtocolSupport::Full),
+            (CHUNK_PROTOCOL_JSON, ProtocolSupport::Full),
+        ],
+        request_response::Config::default(),
+    )
+}
@@ -0,0 +1,273 @@
+use k256::ecdsa::{
+    Signature, SigningKey, VerifyingKey,
+    signature::{Signer, Verifier},
+};
+use k256::elliptic_curve::rand_core::OsRng;
+use libp2p::PeerId;
+use sha2::{Digest, Sha256};
+use std::error::Error;
+use std::path::PathBuf;
+
+use crate::blockchain::Blockchain;
+use crate::keystore;
+use crate::node::{Node, verify_signature};
+use crate::transaction::TransferTx;
+
+/// Wallet addresses are `pc`, the hex of 20 bytes of the key's hash, then a 4 byte checksum
+const ADDRESS_PREFIX: &str = "pc";
+const PAYLOAD_LEN: usize = 20;
+const CHECKSUM_LEN: usize = 4;
+
+fn checksum(payload: &[u8]) -> Vec<u8> {
+    Sha256::digest(Sha256::digest(
+        [ADDRESS_PREFIX.as_bytes(), payload].concat(),
+    ))[..CHECKSUM_LEN]
+        .to_vec()
+}
+
+pub fn address(key: &VerifyingKey) -> String {
+    let payload = &Sha256::digest(key.to_sec1_bytes())[..PAYLOAD_LEN];
+    format!(
+        "{ADDRESS_PREFIX}{}{}",
+        hex::encode(payload),
+        hex::encode(checksum(payload))
+    )
+}
+
+/// Checks the format and checksum of a wallet address, so a mistyped one is refused before coins
+/// go astray
+pub fn is_address(address: &str) -> bool {
+    let Some(bytes) = address
+        .strip_prefix(ADDRESS_PREFIX)
+        .and_then(|rest| hex::decode(rest).ok())
+    else {
+        return false;
+    };
+    bytes.len() == PAYLOAD_LEN + CHECKSUM_LEN
+        && checksum(&bytes[..PAYLOAD_LEN]) == bytes[PAYLOAD_LEN..]
+}
+
+/// Coins can be held by wallet addresses and by nodes, which keep their storage rewards
+pub fn is_account(id: &str) -> bool {
+    is_address(id) || id.parse::<PeerId>().is_ok()
+}
+
+/// A key of the wallet, with its own balance apart from any node
+pub struct Account {
+    pub name: String,
+    pub key: SigningKey,
+}
+
+impl Account {
+    pub fn address(&self) -> String {
+        address(self.key.verifying_key())
+    }
+}
+
+/// Accounts of one operator, sealed on disk like the node key
+pub struct Wallet {
+    path: PathBuf,
+    passphrase: Option<String>,
+    pub accounts: Vec<Account>,
+}
+
+impl Wallet {
+    pub fn load(path: PathBuf, passphrase: Option<String>) -> Result<Self, Box<dyn Error>> {
+        let accounts = match keystore::read_sealed(&path, passphrase.as_deref())? {
+            Some(bytes) => serde_json::from_slice::<Vec<(String, String)>>(&bytes)?
+                .into_iter()
+                .map(|(name, key)| {
+                    Ok(Account {
+                        name,
+                        key: SigningKey::from_slice(&hex::decode(key)?)?,
+                    })
+                })
+                .collect::<Result<Vec<_>, Box<dyn Error>>>()?,
+            None => vec![],
+        };
+        Ok(Wallet {
+            path,
+            passphrase,
+            accounts,
+        })
+    }
+
+    fn save(&self) -> Result<(), Box<dyn Error>> {
+        let accounts: Vec<(String, String)> = self
+            .accounts
+            .iter()
+            .map(|account| (account.name.clone(), hex::encode(account.key.to_bytes())))
+            .collect();
+        keystore::write_sealed(
+            &self.path,
+            &serde_json::to_vec(&accounts)?,
+            self.passphrase.as_deref(),
+        )
+    }
+
+    pub fn create(&mut self, name: &str) -> Result<&Account, Box<dyn Error>> {
+        if self.account(name).is_some() {
+            return Err("account already exists".into());
+        }
+        self.accounts.push(Account {
+            name: name.to_string(),
+            key: SigningKey::random(&mut OsRng),
+        });
+        self.save()?;
+        Ok(self.accounts.last().unwrap())
+    }
+
+    /// Finds an account by name or address
+    pub fn account(&self, name: &str) -> Option<&Account> {
+        self.accounts
+            .iter()
+            .find(|account| account.name == name || account.address() == name)
+    }
+}
+
+impl TransferTx {
+    /// Fields as a JSON array, so that no two transfers sign the same bytes
+    fn signing_bytes(&self) -> Vec<u8> {
+        serde_json::to_vec(&(&self.from, &self.to, self.amount, self.nonce)).unwrap_or_default()
+    }
+
+    fn unsigned(from: String, to: &str, amount: f64, nonce: u64) -> Result<Self, Box<dyn Error>> {
+        if !is_account(to) {
+            return Err("recipient is neither a valid address nor a node id".into());
+        }
+        Ok(TransferTx {
+            from,
+            to: to.to_string(),
+            amount,
+            nonce,
+            signature: vec![],
+            public_key: vec![],
+        })
+    }
+
+    /// Transfer out of a wallet account
+    pub fn new(
+        account: &Account,
+        to: &str,
+        amount: f64,
+        nonce: u64,
+    ) -> Result<Self, Box<dyn Error>> {
+        let mut ttx = Self::unsigned(account.address(), to, amount, nonce)?;
+        let signature: Signature = account.key.sign(&ttx.signing_bytes());
+        ttx.signature = signature.to_vec();
+        ttx.public_key = account.key.verifying_key().to_sec1_bytes().to_vec();
+
+        Ok(ttx)
+    }
+
+    /// Transfer out of the balance a node earned
+    pub fn from_node(
+        node: &Node,
+        to: &str,
+        amount: f64,
+        nonce: u64,
+    ) -> Result<Self, Box<dyn Error>> {
+        let mut ttx = Self::unsigned(node.id.clone(), to, amount, nonce)?;
+        ttx.signature = node.private_key.sign(&ttx.signing_bytes())?;
+        ttx.public_key = node.public_key.clone();
+
+        Ok(ttx)
+    }
+
+    pub fn verify(&self) -> Result<bool, Box<dyn Error>> {
+        if is_address(&self.from) {
+            let key = VerifyingKey::from_sec1_bytes(&self.public_key)?;
+            if address(&key) != self.from {
+                return Ok(false);
+            }
+            let signature = Signature::from_slice(&self.signature)?;
+            return Ok(key.verify(&self.signing_bytes(), &signature).is_ok());
+        }
+        verify_signature(
+            &self.from,
+            &self.public_key,
+            &self.signing_bytes(),
+            &self.signature,
+        )
+    }
+
+    /// Checks that the sender signed the transfer and that it is the sender's next one on
+    /// `blockchain`. Balances also carry proof rewards that never go on-chain, so no two nodes
+    /// agree on them and they are left to the sender.
+    pub fn check(&self, blockchain: &Blockchain) -> Result<(), Box<dyn Error>> {
+        if !self.verify()? {
+            return Err("invalid transfer signature".into());
+        }
+        if !self.amount.is_finite() || self.amount <= 0.0 {
+            return Err("invalid amount".into());
+        }
+        if !is_account(&self.to) || self.to == self.from {
+            return Err("invalid recipient".into());
+        }
+        if self.nonce != blockchain.next_nonce(&self.from) {
+            return Err("transfer is out of order".into());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libp2p::identity;
+
+    fn transfer() -> TransferTx {
+        let account = Account {
+            name: "alice".to_string(),
+            key: SigningKey::random(&mut OsRng),
+        };
+        let to = address(SigningKey::random(&mut OsRng).verifying_key());
+        TransferTx::new(&account, &to, 1.0, 23).unwrap()
+    }
+
+    #[test]
+    fn signed_transfer_verifies() {
+        assert!(transfer().verify().unwrap());
+    }
+
+    #[test]
+    fn changed_amount_or_nonce_fails_to_verify() {
+        // "1" + "23" and "12" + "3" must not sign the same bytes
+        let mut ttx = transfer();
+        ttx.amount = 12.0;
+        ttx.nonce = 3;
+        assert!(!ttx.verify().unwrap());
+
+        let mut ttx = transfer();
+        ttx.amount = 2.0;
+        assert!(!ttx.verify().unwrap());
+
+        let mut ttx = transfer();
+        ttx.nonce = 24;
+        assert!(!ttx.verify().unwrap());
+    }
+
+    #[test]
+    fn funded_transfer_keeps_the_chain_valid() {
+        let node = Node::new(identity::Keypair::generate_ed25519());
+        let mut blockchain = Blockchain::new_with_genesis_block();
+        // earned through a proof, which only this node saw
+        blockchain.balance.insert(node.id.clone(), 0.01);
+
+        let to = address(SigningKey::random(&mut OsRng).verifying_key());
+        let ttx = TransferTx::from_node(&node, &to, 0.01, blockchain.next_nonce(&node.id)).unwrap();
+        blockchain.propose_block(&node, ttx, 1).unwrap();
+
+        // peers replay it from a genesis where nobody has anything
+        assert!(blockchain.verify());
+        assert_eq!(blockchain.balance[&to], 0.01);
+    }
+
+    #[test]
+    fn mistyped_address_is_refused() {
+        let mut address = address(SigningKey::random(&mut OsRng).verifying_key());
+        let last = address.pop().unwrap();
+        address.push(if last == '0' { '1' } else { '0' });
+        assert!(!is_address(&address));
+    }
+}